@@ -1,62 +1,129 @@
-use std::mem::{replace, swap, transmute};
+use std::borrow::Borrow;
+use std::cmp::{min, max, Ordering};
+use std::default::Default;
+use std::marker::PhantomData;
+use std::mem::{replace, swap};
+use std::uint;
+use std::u8;
+use std::fmt;
 use std::fmt::Show;
+use std::io;
+use std::io::IoResult;
+use std::hash::{Hash, Writer};
+use std::iter::{DoubleEndedIterator, FromIterator, Extend, IntoIterator};
+use std::ops::{Index, IndexMut, Sub};
+use std::slice;
 use std::rand;
 use std::rand::distributions::{IndependentSample, Range};
 
 type Link<T> = Option<Box<T>>;
 
-pub struct Tree<K, V> {
+pub struct Tree<K, V, C = Natural> {
     root: Link<Node<K, V>>,
-    size: uint
+    size: uint,
+    cmp: C, // the ordering on keys; every key comparison goes through it
+    policy: DuplicatePolicy
+}
+
+// What `insert` does with a key that is already in the tree
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum DuplicatePolicy {
+    Overwrite, // replace the old value, returning it: the default
+    Keep,      // leave the old value, returning the new one
+    Multi      // keep both, appending the new value to the key's list
+}
+
+// An ordering on keys. Any `Fn(&K, &K) -> Ordering` closure is one, so that
+// `Tree::with_comparator` can take e.g. a reversed or case-insensitive order.
+pub trait Compare<T: ?Sized> {
+    fn compare(&self, a: &T, b: &T) -> Ordering;
+}
+
+// The ordering given by `Ord`, used by `Tree::new`. Since it works for any
+// `Ord` type, not just `K`, it also lets lookups take borrowed forms of keys.
+#[deriving(Clone)]
+pub struct Natural;
+
+impl<T: ?Sized + Ord> Compare<T> for Natural {
+    fn compare(&self, a: &T, b: &T) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+impl<T: ?Sized, F: Fn(&T, &T) -> Ordering> Compare<T> for F {
+    fn compare(&self, a: &T, b: &T) -> Ordering {
+        (*self)(a, b)
+    }
 }
 
+#[deriving(Clone)]
 struct Node<K, V> {
     key: K,
     value: V,
+    // A tree of n nodes has levels of at most log2(n + 1), so a byte is
+    // plenty. It goes next to the entry, where small keys and values leave
+    // room for it, rather than costing a whole word after the links.
+    level: u8,
     left: Link<Node<K, V>>,
     right: Link<Node<K, V>>,
-    level: uint
+    size: uint // number of nodes in the subtree rooted here
 }
 
-impl<K: Ord, V> Node<K, V> {
+impl<K, V> Node<K, V> {
     pub fn new(key: K, value: V) -> Node<K, V> {
-        Node { key: key, value: value, left: None, right: None, level: 1 }
-    }
-
-    fn max(&self) -> &K {
-        match self.right {
-            None => &self.key,
-            Some(ref n) => n.max(),
-        }
+        Node { key: key, value: value, left: None, right: None, level: 1, size: 1 }
     }
 
-    fn min(&self) -> &K {
-        match self.left {
-            None => &self.key,
-            Some(ref n) => n.min(),
+    // A single in-order walk, checking that every key is strictly greater than
+    // the one before it, or only not less if `allow_equal`
+    fn is_bst<C: Compare<K>>(&self, cmp: &C, allow_equal: bool) -> bool {
+        let mut stack = vec!();
+        let mut prev: Option<&K> = None;
+        let mut current = Some(self);
+        loop {
+            match current {
+                Some(n) => {
+                    stack.push(n);
+                    current = n.left.as_ref().map(|l| &**l);
+                },
+                None => match stack.pop() {
+                    None => return true,
+                    Some(n) => {
+                        match prev.map(|p| cmp.compare(p, &n.key)) {
+                            Some(Greater) => return false,
+                            Some(Equal) if !allow_equal => return false,
+                            _ => {}
+                        }
+                        prev = Some(&n.key);
+                        current = n.right.as_ref().map(|r| &**r);
+                    }
+                }
+            }
         }
     }
 
-    fn is_bst(&self) -> bool {
-        self.left.as_ref().map_or(true, |n| n.is_bst() && *n.max() < self.key)
-        && self.right.as_ref().map_or(true, |n| n.is_bst() && *n.min() > self.key)
-    }
-
     // To be an AA tree, it must be a binary search tree and, for all nodes n:
     //   - if n is missing a child, its level must be 1
     //   - the left child must have a level one less than n's level
     //   - the right child must have a level equal to or one less than n's level
     //   - the right child's right child must not have the same level as n's level
-    fn is_aa(&self) -> bool {
-        let lvl = self.level;
+    fn is_aa<C: Compare<K>>(&self, cmp: &C, allow_equal: bool) -> bool {
+        self.is_bst(cmp, allow_equal) && self.has_aa_levels()
+    }
+
+    // the level conditions of `is_aa`, checked separately so that the search
+    // order is only checked once rather than again for every subtree
+    fn has_aa_levels(&self) -> bool {
+        check_node_levels(self).is_ok()
+            && self.left.as_ref().map_or(true, |n| n.has_aa_levels())
+            && self.right.as_ref().map_or(true, |n| n.has_aa_levels())
+    }
 
-        self.is_bst()
-            && !(self.left.is_none() && self.right.is_none() && self.level != 1)
-            && self.left.as_ref().map_or(true, |n| n.is_aa())
-            && self.no_red_left_child()
-            && self.right.as_ref().map_or(true,
-                |n| n.is_aa() && (n.level == lvl || n.level + 1 == lvl)
-                    && !(n.level == lvl && !n.no_red_right_child()))
+    // every node's cached size must be one more than its children's combined
+    fn is_size_consistent(&self) -> bool {
+        self.size == 1 + subtree_size(&self.left) + subtree_size(&self.right)
+            && self.left.as_ref().map_or(true, |n| n.is_size_consistent())
+            && self.right.as_ref().map_or(true, |n| n.is_size_consistent())
     }
 
     fn no_red_left_child(&self) -> bool {
@@ -73,6 +140,37 @@ impl<K: Ord, V> Node<K, V> {
         }
     }
 }
+
+// The level rules of `Node::is_aa`, in the order `check_node_levels` tries them
+#[deriving(Clone, PartialEq)]
+enum LevelRule {
+    Leaf,
+    Left,
+    Right,
+    Grandchild,
+}
+
+// the level rules of `Node::is_aa` for `n` alone, naming the first it breaks
+fn check_node_levels<K, V>(n: &Node<K, V>) -> Result<(), LevelRule> {
+    if n.left.is_none() && n.right.is_none() && n.level != 1 {
+        return Err(LevelRule::Leaf);
+    }
+    if !n.no_red_left_child() {
+        return Err(LevelRule::Left);
+    }
+    match n.right {
+        Some(ref r) => {
+            if r.level != n.level && r.level + 1 != n.level {
+                return Err(LevelRule::Right);
+            }
+            if r.level == n.level && !r.no_red_right_child() {
+                return Err(LevelRule::Grandchild);
+            }
+        },
+        None => {}
+    }
+    Ok(())
+}
  
 // Remove left horizontal link by rotating right
 /*
@@ -82,14 +180,21 @@ impl<K: Ord, V> Node<K, V> {
     \        /
      c      c
 
-  provided that a.level == b.level
+  provided that a.level == b.level, and a no-op otherwise
 */
-fn skew<K: Ord, V>(node: &mut Box<Node<K, V>>) {
-    if node.left.is_some() && node.left.get_ref().level == node.level {
+fn skew<K, V>(node: &mut Box<Node<K, V>>) {
+    // on entry a left child may have caught up with its parent's level, but
+    // never overtaken it
+    debug_assert!(level(&node.left) <= node.level);
+
+    // a missing child counts as level 0, so this also rules out an empty left
+    if level(&node.left) == node.level {
         let mut save = node.left.take_unwrap();
         swap(&mut node.left, &mut save.right); // save.right now None
         swap(node, &mut save);
+        update_size(&mut save);
         node.right = Some(save);
+        update_size(node);
     }
 }
 
@@ -103,57 +208,644 @@ fn skew<K: Ord, V>(node: &mut Box<Node<K, V>>) {
      / \        \
     d   c        d
 
-  provided that a.level == c.level
+  provided that a.level == c.level, and a no-op otherwise
 */
-fn split<K: Ord, V>(node: &mut Box<Node<K, V>>) {
-    if node.right.as_ref().map_or(false,
-      |x| x.right.is_some() && x.right.get_ref().level == node.level) {
+fn split<K, V>(node: &mut Box<Node<K, V>>) {
+    // on entry neither the right child nor its right child is above the level
+    // of its parent
+    debug_assert!(level(&node.right) <= node.level);
+    let double_horizontal = match node.right {
+        Some(ref r) => {
+            debug_assert!(level(&r.right) <= r.level);
+            level(&r.right) == node.level
+        },
+        None => false,
+    };
+
+    if double_horizontal {
         let mut save = node.right.take_unwrap();
         swap(&mut node.right, &mut save.left); // save.left now None
+        debug_assert!(save.level < u8::MAX);
         save.level += 1;
         swap(node, &mut save);
+        update_size(&mut save);
         node.left = Some(save);
+        update_size(node);
+        debug_assert!(node.level as uint <= max_level(node.size));
+    }
+}
+
+// Number of nodes in the subtree at `link`
+fn subtree_size<K, V>(link: &Link<Node<K, V>>) -> uint {
+    link.as_ref().map_or(0, |n| n.size)
+}
+
+// Recompute a node's cached size from its children's
+fn update_size<K, V>(node: &mut Box<Node<K, V>>) {
+    node.size = 1 + subtree_size(&node.left) + subtree_size(&node.right);
+}
+
+// Level of the node at `link`, treating a missing node as level 0
+fn level<K, V>(link: &Link<Node<K, V>>) -> u8 {
+    link.as_ref().map_or(0, |n| n.level)
+}
+
+// The highest level the root of an AA subtree of `size` nodes can have,
+// floor(log2(size + 1)): a node at level l has a left child at level l - 1,
+// and so on down, so its subtree holds at least 2^l - 1 nodes. Levels are
+// therefore below `uint::BITS`, well within a `u8`.
+fn max_level(size: uint) -> uint {
+    uint::BITS - 1 - (size + 1).leading_zeros()
+}
+
+// After a deletion, a node's level may be too high relative to its children.
+// Lower it to one more than its lowest child, and drag a horizontally linked
+// right child down along with it.
+fn decrease_level<K, V>(node: &mut Box<Node<K, V>>) {
+    let should_be = min(level(&node.left), level(&node.right)) + 1;
+    if should_be < node.level {
+        node.level = should_be;
+        match node.right {
+            Some(ref mut r) => if should_be < r.level { r.level = should_be },
+            None => {}
+        }
+    }
+}
+
+// Rebalance a node on the search path of a deletion: refresh its cached size
+// and decrease its level, then
+// skew and split enough of its right spine to remove any horizontal links
+// that the level change introduced
+fn fix_after_remove<K, V>(node: &mut Box<Node<K, V>>) {
+    update_size(node);
+    decrease_level(node);
+    skew(node);
+    match node.right {
+        Some(ref mut r) => {
+            skew(r);
+            match r.right {
+                Some(ref mut rr) => skew(rr),
+                None => {}
+            }
+        },
+        None => {}
+    }
+    split(node);
+    match node.right {
+        Some(ref mut r) => split(r),
+        None => {}
+    }
+}
+
+// Standard recursive AA insertion into the subtree at `link`: returns
+// `Some((key, v))` iff `v` was already associated with `key`, handing back the
+// unused `key` along with `v`, and otherwise skews and splits each node on the
+// way back up the search path
+fn insert_node<K, V, C: Compare<K>>(link: &mut Link<Node<K, V>>, key: K, value: V,
+                                    cmp: &C) -> Option<(K, V)> {
+    if link.is_none() {
+        *link = Some(box Node::new(key, value));
+        return None;
+    }
+
+    let n = link.get_mut_ref();
+    let old = match cmp.compare(&key, &n.key) {
+        Less => insert_node(&mut n.left, key, value, cmp),
+        Greater => insert_node(&mut n.right, key, value, cmp),
+        Equal => return Some((key, replace(&mut n.value, value)))
+    };
+    if old.is_none() {
+        update_size(n);
+        skew(n);
+        split(n);
+    }
+    old
+}
+
+// Like `insert_node`, but always inserts, for `DuplicatePolicy::Multi`. A key
+// already present sends the search right, so the new entry lands after every
+// entry with an equal key, keeping those in insertion order.
+fn insert_multi_node<K, V, C: Compare<K>>(link: &mut Link<Node<K, V>>, key: K, value: V,
+                                          cmp: &C) {
+    if link.is_none() {
+        *link = Some(box Node::new(key, value));
+        return;
+    }
+
+    let n = link.get_mut_ref();
+    match cmp.compare(&key, &n.key) {
+        Less => insert_multi_node(&mut n.left, key, value, cmp),
+        _ => insert_multi_node(&mut n.right, key, value, cmp),
+    }
+    update_size(n);
+    skew(n);
+    split(n);
+}
+
+// Inserts a key greater than every key in the subtree at `link` at the bottom
+// of its right spine. The path never turns left, so no key comparisons are
+// needed, and no left links change, so each node on the way back up only
+// needs its size updated and a split, never a skew.
+fn push_max_node<K, V>(link: &mut Link<Node<K, V>>, key: K, value: V) {
+    match *link {
+        None => *link = Some(box Node::new(key, value)),
+        Some(ref mut n) => {
+            push_max_node(&mut n.right, key, value);
+            n.size += 1;
+            split(n);
+        }
+    }
+}
+
+// Like `insert_node`, but only inserts (a value made by `default`) if `key` is
+// absent. Returns a pointer to the value now under `key`, along with `key`
+// itself if it went unused because it was already present. The pointer stays
+// valid through the rebalancing, since rotations relink the boxes without
+// moving the nodes inside them.
+fn find_or_insert_node<K, V, C: Compare<K>, F: FnOnce() -> V>(link: &mut Link<Node<K, V>>,
+                                                              key: K, default: F,
+                                                              cmp: &C) -> (*mut V, Option<K>) {
+    if link.is_none() {
+        *link = Some(box Node::new(key, default()));
+        return (&mut link.get_mut_ref().value as *mut V, None);
+    }
+
+    let n = link.get_mut_ref();
+    let (value, unused) = match cmp.compare(&key, &n.key) {
+        Less => find_or_insert_node(&mut n.left, key, default, cmp),
+        Greater => find_or_insert_node(&mut n.right, key, default, cmp),
+        Equal => return (&mut n.value as *mut V, Some(key))
+    };
+    if unused.is_none() {
+        update_size(n);
+        skew(n);
+        split(n);
+    }
+    (value, unused)
+}
+
+// A copy of the subtree at `link`, down to the levels, with each value passed
+// through `f`. The left subtree is copied first, so `f` sees the values in
+// ascending order of key.
+fn map_link<K: Clone, V, W, F: FnMut(&V) -> W>(link: &Link<Node<K, V>>,
+                                               f: &mut F) -> Link<Node<K, W>> {
+    match *link {
+        None => None,
+        Some(ref n) => {
+            let left = map_link(&n.left, f);
+            let value = (*f)(&n.value);
+            Some(box Node { key: n.key.clone(),
+                            value: value,
+                            level: n.level,
+                            left: left,
+                            right: map_link(&n.right, f),
+                            size: n.size })
+        }
+    }
+}
+
+// folds `f` over the values of the subtree at `link`, in order of key
+fn fold_link<K, V, B, F: FnMut(B, &V) -> B>(link: &Link<Node<K, V>>, init: B,
+                                            f: &mut F) -> B {
+    match *link {
+        None => init,
+        Some(ref n) => {
+            let acc = fold_link(&n.left, init, f);
+            let acc = (*f)(acc, &n.value);
+            fold_link(&n.right, acc, f)
+        }
+    }
+}
+
+// Finds the values for `keys`, which must be sorted and distinct, in the
+// subtree at `link`, storing each in `found` at the index it is paired with.
+// Every node is split into its fields, and the keys below and above the
+// node's go to its left and right subtrees, so each value is borrowed from a
+// different node. Returns false if some key is missing.
+fn find_many_mut<'a, K, V, C: Compare<K>>(link: &'a mut Link<Node<K, V>>,
+                                          keys: &[(uint, &K)],
+                                          found: &mut Vec<Option<&'a mut V>>,
+                                          cmp: &C) -> bool {
+    if keys.is_empty() {
+        return true;
+    }
+    match *link {
+        None => false,
+        Some(ref mut n) => {
+            let Node { ref key, ref mut value, ref mut left, ref mut right, .. } = **n;
+            let below = keys.iter()
+                            .take_while(|&&(_, k)| cmp.compare(k, key) == Less)
+                            .count();
+            let mut above = below;
+            if above < keys.len() {
+                let (i, k) = keys[above];
+                if cmp.compare(k, key) == Equal {
+                    *found.get_mut(i) = Some(value);
+                    above += 1;
+                }
+            }
+            find_many_mut(left, keys.slice_to(below), found, cmp)
+                && find_many_mut(right, keys.slice_from(above), found, cmp)
+        }
+    }
+}
+
+// the value for `key` in the subtree at `link`, found as in `Tree::get_mut`
+fn find_link_mut<'a, K, V, C: Compare<K>>(link: &'a mut Link<Node<K, V>>, key: &K,
+                                          cmp: &C) -> Option<&'a mut V> {
+    let mut current = link;
+    loop {
+        let link = current; // move the borrow so it can be replaced below
+        match *link {
+            Some(ref mut n) => {
+                match cmp.compare(key, &n.key) {
+                    Less => current = &mut n.left,
+                    Greater => current = &mut n.right,
+                    Equal => return Some(&mut n.value)
+                }
+            }
+            None => return None
+        }
+    }
+}
+
+// The values for `a` and `b` in the subtree at `link`. Both searches share a
+// path until the keys go different ways, and from that node on each one takes
+// a different field of it, so the two references always come from distinct
+// nodes. If `a` and `b` are equal, only `a` gets the value.
+fn find_pair_mut<'a, K, V, C: Compare<K>>(link: &'a mut Link<Node<K, V>>, a: &K, b: &K,
+                                          cmp: &C)
+                                          -> (Option<&'a mut V>, Option<&'a mut V>) {
+    match *link {
+        None => (None, None),
+        Some(ref mut n) => {
+            let Node { ref key, ref mut value, ref mut left, ref mut right, .. } = **n;
+            match (cmp.compare(a, key), cmp.compare(b, key)) {
+                (Less, Less) => find_pair_mut(left, a, b, cmp),
+                (Greater, Greater) => find_pair_mut(right, a, b, cmp),
+                (Less, Greater) => (find_link_mut(left, a, cmp), find_link_mut(right, b, cmp)),
+                (Greater, Less) => (find_link_mut(right, a, cmp), find_link_mut(left, b, cmp)),
+                (Less, Equal) => (find_link_mut(left, a, cmp), Some(value)),
+                (Greater, Equal) => (find_link_mut(right, a, cmp), Some(value)),
+                (Equal, Less) => (Some(value), find_link_mut(left, b, cmp)),
+                (Equal, Greater) => (Some(value), find_link_mut(right, b, cmp)),
+                (Equal, Equal) => (Some(value), None),
+            }
+        }
+    }
+}
+
+// Standard recursive AA deletion from the subtree at `link`: returns `Some((k,
+// v))` iff `key` was present, stored as `k` and associated with `v`, and
+// rebalances each node on the way back up the search path
+fn remove_node<K, V, Q: ?Sized, C: Compare<Q>>(link: &mut Link<Node<K, V>>, key: &Q,
+                                               cmp: &C) -> Option<(K, V)>
+    where K: Borrow<Q> {
+    let ord = match *link {
+        None => return None,
+        Some(ref n) => cmp.compare(key, n.key.borrow()),
+    };
+
+    let removed = match ord {
+        Less => remove_node(&mut link.get_mut_ref().left, key, cmp),
+        Greater => remove_node(&mut link.get_mut_ref().right, key, cmp),
+        Equal => return Some(remove_root(link))
+    };
+    if removed.is_some() {
+        fix_after_remove(link.get_mut_ref());
+    }
+    removed
+}
+
+// Removes the entry at the root of the (non-empty) subtree at `link`. A node
+// without a right child is a leaf (a left child would force a right child, by
+// the level rules). Otherwise, the entry is replaced with its in-order
+// successor's, and that node is removed instead.
+fn remove_root<K, V>(link: &mut Link<Node<K, V>>) -> (K, V) {
+    if link.get_ref().right.is_none() {
+        let Node { key, value, .. } = *link.take_unwrap();
+        return (key, value);
+    }
+    let n = link.get_mut_ref();
+    let (k, v) = remove_min(&mut n.right);
+    let removed = (replace(&mut n.key, k), replace(&mut n.value, v));
+    fix_after_remove(n);
+    removed
+}
+
+// Which child to step down to, on the way from the root to an entry
+#[deriving(Clone, PartialEq)]
+enum Step {
+    Left,
+    Right
+}
+
+// the node that `path` leads to from `link`
+fn node_at<'a, K, V>(link: &'a Link<Node<K, V>>, path: &[Step]) -> &'a Node<K, V> {
+    let mut n = &**link.get_ref();
+    for step in path.iter() {
+        n = match *step {
+            Step::Left => &**n.left.get_ref(),
+            Step::Right => &**n.right.get_ref(),
+        };
+    }
+    n
+}
+
+// `node_at`, mutably
+fn node_at_mut<'a, K, V>(link: &'a mut Link<Node<K, V>>,
+                         path: &[Step]) -> &'a mut Node<K, V> {
+    let mut n = &mut **link.get_mut_ref();
+    for step in path.iter() {
+        let current = n; // move the borrow so it can be replaced
+        n = match *step {
+            Step::Left => &mut **current.left.get_mut_ref(),
+            Step::Right => &mut **current.right.get_mut_ref(),
+        };
+    }
+    n
+}
+
+// the path from `link` down to the entry with exactly `n` smaller keys in its
+// subtree, steering by the cached sizes as `Tree::select` does
+fn path_to_index<K, V>(link: &Link<Node<K, V>>, n: uint) -> Vec<Step> {
+    let mut path = vec!();
+    let mut n = n;
+    let mut current = link;
+    loop {
+        match *current {
+            Some(ref r) => {
+                let left = subtree_size(&r.left);
+                match n.cmp(&left) {
+                    Less => {
+                        path.push(Step::Left);
+                        current = &r.left;
+                    },
+                    Greater => {
+                        n -= left + 1;
+                        path.push(Step::Right);
+                        current = &r.right;
+                    },
+                    Equal => return path
+                }
+            }
+            None => fail!("path_to_index: index out of bounds")
+        }
     }
 }
 
+// the path from `link` down to the end of its spine on the `step` side, i.e.
+// to its smallest key for `Left` and its largest for `Right`
+fn spine_path<K, V>(link: &Link<Node<K, V>>, step: Step) -> Vec<Step> {
+    let mut path = vec!();
+    let mut current = link;
+    loop {
+        match *current {
+            Some(ref n) => {
+                let next = match step {
+                    Step::Left => &n.left,
+                    Step::Right => &n.right,
+                };
+                if next.is_none() {
+                    return path;
+                }
+                path.push(step.clone());
+                current = next;
+            },
+            None => return path,
+        }
+    }
+}
+
+// Like `find_or_insert_node` for a key known to be absent, but steering by
+// `path`, which must lead from `link` to the empty link where the key belongs,
+// rather than by comparing keys. The returned pointer stays valid in the same
+// way.
+fn insert_at<K, V>(link: &mut Link<Node<K, V>>, path: &[Step], key: K, value: V) -> *mut V {
+    if link.is_none() {
+        *link = Some(box Node::new(key, value));
+        return &mut link.get_mut_ref().value as *mut V;
+    }
+
+    let n = link.get_mut_ref();
+    let inserted = match path.head() {
+        Some(&Step::Left) => insert_at(&mut n.left, path.tail(), key, value),
+        Some(&Step::Right) => insert_at(&mut n.right, path.tail(), key, value),
+        None => fail!("insert_at: path leads to an occupied link"),
+    };
+    update_size(n);
+    skew(n);
+    split(n);
+    inserted
+}
+
+// Like `remove_node`, but finds the entry to remove by following `path` rather
+// than by comparing keys
+fn remove_at<K, V>(link: &mut Link<Node<K, V>>, path: &[Step]) -> (K, V) {
+    let removed = match path.head() {
+        None => return remove_root(link),
+        Some(&Step::Left) => remove_at(&mut link.get_mut_ref().left, path.tail()),
+        Some(&Step::Right) => remove_at(&mut link.get_mut_ref().right, path.tail()),
+    };
+    fix_after_remove(link.get_mut_ref());
+    removed
+}
+
+// Removes the smallest entry of the (non-empty) subtree at `link`, which
+// lives at the bottom of the left spine and has no left child
+fn remove_min<K, V>(link: &mut Link<Node<K, V>>) -> (K, V) {
+    if link.get_ref().left.is_none() {
+        let mut n = link.take_unwrap();
+        *link = n.right.take();
+        let Node { key, value, .. } = *n;
+        return (key, value);
+    }
+
+    let min = remove_min(&mut link.get_mut_ref().left);
+    fix_after_remove(link.get_mut_ref());
+    min
+}
+
+// Removes the largest entry of the (non-empty) subtree at `link`. It lives at
+// the bottom of the right spine and, lacking a right child, is a leaf.
+fn remove_max<K, V>(link: &mut Link<Node<K, V>>) -> (K, V) {
+    if link.get_ref().right.is_none() {
+        let Node { key, value, .. } = *link.take_unwrap();
+        return (key, value);
+    }
+
+    let max = remove_max(&mut link.get_mut_ref().right);
+    fix_after_remove(link.get_mut_ref());
+    max
+}
+
+// Drops the nodes in `worklist` along with all their descendants. Each node
+// is unlinked from its children, which join the worklist, before it is
+// dropped, so that a deep tree can't overflow the stack with nested `Box`
+// drops.
+fn drop_nodes<K, V>(mut worklist: Vec<Box<Node<K, V>>>) {
+    loop {
+        match worklist.pop() {
+            None => break,
+            Some(mut n) => {
+                match n.left.take() {
+                    Some(l) => worklist.push(l),
+                    None => {}
+                }
+                match n.right.take() {
+                    Some(r) => worklist.push(r),
+                    None => {}
+                }
+                // `n` is now childless, so dropping it here doesn't recurse
+            }
+        }
+    }
+}
+
+// Build a perfectly balanced subtree from the next `n` entries of `entries`,
+// which must come in ascending order of key. Giving each node a level one
+// more than its left child's satisfies the AA invariants for this shape: the
+// right subtree is never smaller than the left, nor so much bigger that its
+// root's right child ends up on the same level as the node.
+fn build_balanced<K, V, I: Iterator<(K, V)>>(entries: &mut I,
+                                             n: uint) -> Link<Node<K, V>> {
+    if n == 0 {
+        return None;
+    }
+    let left = build_balanced(entries, (n - 1) / 2);
+    let (key, value) = entries.next().unwrap();
+    let right = build_balanced(entries, n - 1 - (n - 1) / 2);
+
+    let mut node = box Node::new(key, value);
+    node.level = level(&left) + 1;
+    node.size = n;
+    node.left = left;
+    node.right = right;
+    Some(node)
+}
+
 impl<K: Ord, V> Tree<K, V> {
-    fn new() -> Tree<K, V> {
-        Tree { root: None, size: 0 }
+    // an empty tree ordered by the keys' `Ord` impl
+    pub fn new() -> Tree<K, V> {
+        Tree::with_comparator(Natural)
+    }
+
+    // an empty tree whose `insert` treats a key already present as `policy` says
+    pub fn with_policy(policy: DuplicatePolicy) -> Tree<K, V> {
+        let mut t = Tree::new();
+        t.policy = policy;
+        t
+    }
+
+    // Builds a perfectly balanced tree in O(n) from entries in strictly
+    // ascending order of key, without any of the rotations `insert` would do
+    pub fn from_sorted(pairs: Vec<(K, V)>) -> Tree<K, V> {
+        debug_assert!(pairs.as_slice().windows(2).all(|w| {
+            let (ref a, _) = w[0];
+            let (ref b, _) = w[1];
+            a < b
+        }), "from_sorted: keys are not strictly ascending");
+
+        let size = pairs.len();
+        let mut t = Tree::new();
+        t.root = build_balanced(&mut pairs.into_iter(), size);
+        t.size = size;
+        t
+    }
+
+    // The entry whose key is nearest to `key`, by the difference between them.
+    // Only the floor and the ceiling of `key` can be nearest; when they're
+    // equally far away, the floor (the smaller key) wins. Only for the natural
+    // order, in which the floor is never greater than `key`.
+    pub fn find_closest<'a>(&'a self, key: &K) -> Option<(&'a K, &'a V)>
+        where K: Sub<K, K> {
+        match (self.floor(key), self.ceiling(key)) {
+            (Some((lo, lv)), Some((hi, hv))) => {
+                if *hi - *key < *key - *lo {
+                    Some((hi, hv))
+                } else {
+                    Some((lo, lv))
+                }
+            },
+            (below, None) => below,
+            (None, above) => above,
+        }
+    }
+}
+
+impl<K, V, C: Compare<K>> Tree<K, V, C> {
+    // an empty tree whose keys are ordered by `cmp` rather than by `Ord`
+    pub fn with_comparator(cmp: C) -> Tree<K, V, C> {
+        Tree { root: None, size: 0, cmp: cmp, policy: DuplicatePolicy::Overwrite }
+    }
+
+    // number of distinct keys in the tree
+    pub fn len(&self) -> uint {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
     }
 
-    fn is_bst(&self) -> bool {
+    // Whether every key is strictly greater than the one before it, in the
+    // order the tree is laid out (under `DuplicatePolicy::Multi`, not less than
+    // it). Only a bug could make this false, so it is meant for assertions in
+    // tests.
+    pub fn is_bst(&self) -> bool {
         match self.root {
             None => true,
-            Some(ref r) => (*r).is_bst()
+            Some(ref r) => (*r).is_bst(&self.cmp, self.policy == DuplicatePolicy::Multi)
         }
     }
 
-    fn is_aa(&self) -> bool {
+    // Whether the tree satisfies all of the AA tree invariants, search order
+    // included. Like `is_bst`, this is for assertions in tests.
+    pub fn is_aa(&self) -> bool {
         match self.root {
             None => true,
-            Some(ref r) => (*r).is_aa()
+            Some(ref r) => (*r).is_aa(&self.cmp, self.policy == DuplicatePolicy::Multi)
         }
     }
 
-    // standard binary search tree lookup, only iterative instead of recursive
-    fn find<'a>(&'a self, key: &K) -> Option<&'a V> {
-        let mut current: &Link<Node<K, V>> = &self.root;
+    fn is_size_consistent(&self) -> bool {
+        subtree_size(&self.root) == self.size
+            && self.root.as_ref().map_or(true, |r| r.is_size_consistent())
+    }
+
+    // Whether `size` matches the number of nodes actually in the tree. The
+    // nodes are counted by walking them with an explicit stack rather than
+    // through `iter`, whose length comes from `size` itself
+    pub fn verify_size(&self) -> bool {
+        let mut count = 0u;
+        let mut stack = vec!(&self.root);
         loop {
-            match *current {
-                Some(ref r) => {
-                    match key.cmp(&r.key) {
-                        Less => current = &r.left,
-                        Greater => current = &r.right,
-                        Equal => return Some(&r.value)
-                    }
-                }
-                None => return None
+            match stack.pop() {
+                None => return count == self.size,
+                Some(&Some(ref n)) => {
+                    count += 1;
+                    stack.push(&n.left);
+                    stack.push(&n.right);
+                },
+                Some(&None) => {}
             }
         }
     }
 
-    // returns `Some(v)` iff `v` was already associated with `key`
-    fn insert(&mut self, key: K, value: V) -> Option<V> {
+    // overwrites the cached size, so that tests can check `verify_size` catches
+    // a wrong one
+    #[cfg(test)]
+    fn set_size_for_test(&mut self, n: uint) {
+        self.size = n;
+    }
+
+    // The iterative, raw pointer `insert` that `insert_node` replaced, kept so
+    // that tests can check the two agree. It records the search path as
+    // pointers, then skews and splits back up along it.
+    #[cfg(test)]
+    fn insert_raw(&mut self, key: K, value: V) -> Option<V> {
+        use std::mem::transmute;
+
         let mut current = &mut self.root as *mut Link<Node<K,V>>;
         let mut path: Vec<*mut Box<Node<K,V>>> = vec!();
         loop { unsafe {
@@ -165,6 +857,7 @@ impl<K: Ord, V> Tree<K, V> {
                             None => break,
                             Some(n) => {
                                 let n: &mut Box<Node<K,V>> = transmute(n);
+                                update_size(n);
                                 skew(n);
                                 split(n);
                             }
@@ -174,7 +867,7 @@ impl<K: Ord, V> Tree<K, V> {
                     return None;
                 },
                 Some(ref mut n) => {
-                    match key.cmp(&n.key) {
+                    match self.cmp.compare(&key, &n.key) {
                         Less => {
                             path.push(n as *mut Box<Node<K,V>>);
                             current = &mut n.left as *mut Link<Node<K,V>>;
@@ -192,157 +885,4450 @@ impl<K: Ord, V> Tree<K, V> {
             }
         }}
     }
-}
 
-fn print_node_depth<K: Show, V: Show>(node: &Link<Node<K,V>>, depth: uint) {
-    let mut pre = "".to_string();
-    if depth > 0 {
-        for i in range(0, depth) {
-            pre = pre + "   ";
+    // standard binary search tree lookup, only iterative instead of recursive.
+    // The key can be any borrowed form of `K` that the comparator can order (as
+    // for `contains_key`, `get_mut` and `remove`), so e.g. a tree with `String`
+    // keys can take a `&str`. Under `DuplicatePolicy::Multi`, this finds one of
+    // the values under `key`, not necessarily the first: see `get_all`.
+    pub fn find<'a, Q: ?Sized>(&'a self, key: &Q) -> Option<&'a V>
+        where K: Borrow<Q>, C: Compare<Q> {
+        let mut current: &Link<Node<K, V>> = &self.root;
+        loop {
+            match *current {
+                Some(ref r) => {
+                    match self.cmp.compare(key, r.key.borrow()) {
+                        Less => current = &r.left,
+                        Greater => current = &r.right,
+                        Equal => return Some(&r.value)
+                    }
+                }
+                None => return None
+            }
         }
     }
 
-    match *node {
-        Some(ref n) => {
-            println!("{}{}:{}*{}", pre, n.key, n.value, n.level);
-            print_node_depth(&n.left, depth + 1);
-            print_node_depth(&n.right, depth + 1);
-        },
-        None => println!("{}-", pre),
+    // same as `find`, but also returns the key as stored in the tree, which may
+    // carry more than the parts of it that the comparator looks at
+    pub fn get_key_value<'a, Q: ?Sized>(&'a self, key: &Q) -> Option<(&'a K, &'a V)>
+        where K: Borrow<Q>, C: Compare<Q> {
+        let mut current: &Link<Node<K, V>> = &self.root;
+        loop {
+            match *current {
+                Some(ref r) => {
+                    match self.cmp.compare(key, r.key.borrow()) {
+                        Less => current = &r.left,
+                        Greater => current = &r.right,
+                        Equal => return Some((&r.key, &r.value))
+                    }
+                }
+                None => return None
+            }
+        }
     }
-}
 
-fn print_tree<K: Show + Ord, V: Show>(tree: &Tree<K, V>) {
-    print_node_depth(&tree.root, 0);
-    println!("Is AA: {}", tree.is_aa());
-    println!("------------");
-}
+    // same descent as `find`, but without producing a borrow of the value
+    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, C: Compare<Q> {
+        let mut current: &Link<Node<K, V>> = &self.root;
+        loop {
+            match *current {
+                Some(ref r) => {
+                    match self.cmp.compare(key, r.key.borrow()) {
+                        Less => current = &r.left,
+                        Greater => current = &r.right,
+                        Equal => return true
+                    }
+                }
+                None => return false
+            }
+        }
+    }
 
-fn main() {
-    let mut t = Tree::new();
-    print_tree(&t);
+    // Every value under `key`, in the order they were inserted. That is at most
+    // one except under `DuplicatePolicy::Multi`, where the entries for a key
+    // sit next to each other in order, so this walks them from the first.
+    pub fn get_all<'a>(&'a self, key: &K) -> Vec<&'a V> {
+        self.iter_from(key)
+            .take_while(|&(k, _)| self.cmp.compare(k, key) == Equal)
+            .map(|(_, v)| v)
+            .collect()
+    }
 
-    t.insert('e', 5u);
-    print_tree(&t);
+    // same as `find`, but the descent threads `&mut` borrows down the tree
+    pub fn get_mut<'a, Q: ?Sized>(&'a mut self,
+                                        key: &Q) -> Option<&'a mut V>
+        where K: Borrow<Q>, C: Compare<Q> {
+        let mut current: &'a mut Link<Node<K, V>> = &mut self.root;
+        loop {
+            let link = current; // move the borrow so it can be replaced below
+            match *link {
+                Some(ref mut r) => {
+                    match self.cmp.compare(key, r.key.borrow()) {
+                        Less => current = &mut r.left,
+                        Greater => current = &mut r.right,
+                        Equal => return Some(&mut r.value)
+                    }
+                }
+                None => return None
+            }
+        }
+    }
 
-    t.insert('b', 88u);
-    print_tree(&t);
+    // applies `f` to the value for `key`, returning whether there was one
+    pub fn update<F>(&mut self, key: &K, f: F) -> bool where F: FnOnce(&mut V) {
+        match self.get_mut(key) {
+            Some(v) => {
+                f(v);
+                true
+            },
+            None => false,
+        }
+    }
 
-    t.insert('d', 11u);
-    print_tree(&t);
+    // Mutable references to the values under each of `keys`, in the same order,
+    // or `None` if any key is missing or appears more than once. Distinct keys
+    // live in distinct nodes, so the references never alias.
+    pub fn get_many_mut<'a>(&'a mut self, keys: &[&K]) -> Option<Vec<&'a mut V>> {
+        let mut sorted: Vec<(uint, &K)> = keys.iter().map(|&k| k).enumerate().collect();
+        sorted.sort_by(|&(_, a), &(_, b)| self.cmp.compare(a, b));
+        if sorted.as_slice().windows(2).any(|w| {
+            let (_, a) = w[0];
+            let (_, b) = w[1];
+            self.cmp.compare(a, b) == Equal
+        }) {
+            return None;
+        }
 
-    let mut t = Tree::new();
-    t.insert(7u, ());
+        let mut found = Vec::from_fn(keys.len(), |_| None);
+        if !find_many_mut(&mut self.root, sorted.as_slice(), &mut found, &self.cmp) {
+            return None;
+        }
+        Some(found.into_iter().map(|v| v.unwrap()).collect())
+    }
+
+    // Mutable references to the values under `a` and `b`, either of which may
+    // be missing. The keys must differ: that is only checked in debug builds,
+    // and otherwise the second reference is always `None`, so the two never
+    // alias. A cheaper `get_many_mut` for the common case of two keys.
+    pub fn get_pair_mut<'a>(&'a mut self, a: &K, b: &K)
+                            -> (Option<&'a mut V>, Option<&'a mut V>) {
+        debug_assert!(self.cmp.compare(a, b) != Equal);
+        find_pair_mut(&mut self.root, a, b, &self.cmp)
+    }
+
+    // Returns `Some(v)` iff `v` was already associated with `key`. In that case
+    // only the value is replaced: the tree keeps the key it already had, and
+    // `key` is dropped, so it neither changes `size` nor costs a needless move.
+    // Under `DuplicatePolicy::Keep`, the old value stays instead, and it's the
+    // new one that comes back. Under `DuplicatePolicy::Multi`, both stay: the
+    // new value goes in as an entry of its own, after the others under `key`,
+    // and `None` comes back.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.policy {
+            DuplicatePolicy::Overwrite => {},
+            DuplicatePolicy::Keep => {
+                return self.try_insert(key, value).err().map(|(_, v)| v);
+            },
+            DuplicatePolicy::Multi => {
+                insert_multi_node(&mut self.root, key, value, &self.cmp);
+                self.size += 1;
+                return None;
+            },
+        }
+        let old = insert_node(&mut self.root, key, value, &self.cmp);
+        if old.is_none() {
+            self.size += 1;
+        }
+        old.map(|(_, v)| v)
+    }
+
+    // Returns the value for `key`, first inserting `default()` if `key` is
+    // absent. Unlike a `find` followed by an `insert`, this descends the tree
+    // only once, and only calls `default` when it actually inserts.
+    pub fn get_or_insert_with<F>(&mut self, key: K, default: F) -> &mut V
+        where F: FnOnce() -> V {
+        let (value, unused) = find_or_insert_node(&mut self.root, key, default, &self.cmp);
+        if unused.is_none() {
+            self.size += 1;
+        }
+        // SAFETY: `value` points into a node's heap box. Rebalancing on the way
+        // back up only swaps and relinks boxes, never moving or freeing a node,
+        // and the reference borrows `self` mutably, so nothing can change the
+        // tree while it lives.
+        unsafe { &mut *value }
+    }
+
+    // Inserts `value` under `key` only if `key` is absent, returning the value
+    // now in the tree. Otherwise the tree is left alone and the pair is handed
+    // back.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<&mut V, (K, V)> {
+        let mut pending = Some(value);
+        let (inserted, unused) = find_or_insert_node(&mut self.root, key,
+                                                     || pending.take().unwrap(),
+                                                     &self.cmp);
+        match unused {
+            Some(key) => Err((key, pending.take().unwrap())),
+            None => {
+                self.size += 1;
+                // SAFETY: as in `get_or_insert_with`, the node holding the value
+                // stays in its box through the rebalancing, and `self` stays
+                // borrowed for as long as the reference
+                Ok(unsafe { &mut *inserted })
+            }
+        }
+    }
+
+    // Inserts every pair from `iter`, returning `(key, old value)` for each key
+    // that was already present, in the order they came. The new value replaces
+    // the old whatever the duplicate policy, and the tree keeps its own copy
+    // of the key.
+    pub fn insert_many<I: Iterator<(K, V)>>(&mut self, mut iter: I) -> Vec<(K, V)> {
+        let mut overwritten = vec!();
+        for (k, v) in iter {
+            match insert_node(&mut self.root, k, v, &self.cmp) {
+                Some(pair) => overwritten.push(pair),
+                None => self.size += 1,
+            }
+        }
+        overwritten
+    }
+
+    // Inserts every pair from `iter`, the new value replacing the old for keys
+    // already present whatever the duplicate policy, and returns how many of
+    // the keys were new
+    pub fn extend_replacing<I: Iterator<(K, V)>>(&mut self, mut iter: I) -> uint {
+        let mut added = 0;
+        for (k, v) in iter {
+            if insert_node(&mut self.root, k, v, &self.cmp).is_none() {
+                added += 1;
+            }
+        }
+        self.size += added;
+        added
+    }
+
+    // Inserts `value` under `key` if the key is new; otherwise `merge` combines
+    // it into the value already there, e.g. to add to a running total. Returns
+    // the value now under `key`, which is always `Some`.
+    pub fn insert_with<F>(&mut self, key: K, value: V, merge: F) -> Option<&mut V>
+        where F: FnOnce(&mut V, V) {
+        match self.entry(key) {
+            Entry::Occupied(entry) => {
+                let existing = entry.into_mut();
+                merge(&mut *existing, value);
+                Some(existing)
+            },
+            Entry::Vacant(entry) => Some(entry.insert(value)),
+        }
+    }
+
+    // Inserts an entry whose key is greater than every key already in the tree,
+    // as when loading entries that arrive in order, skipping the comparisons
+    // `insert` would make. The order is only checked in debug builds.
+    pub fn push_ascending(&mut self, key: K, value: V) {
+        debug_assert!(self.last_key_value().map_or(true,
+            |(max, _)| self.cmp.compare(max, &key) == Less),
+            "push_ascending: key is not greater than the current maximum");
+        push_max_node(&mut self.root, key, value);
+        self.size += 1;
+    }
+
+    // the value under `key`, after inserting `V`'s default value if there
+    // wasn't one, as in `*tree.entry_or_default(k) += 1`
+    pub fn entry_or_default(&mut self, key: K) -> &mut V where V: Default {
+        self.get_or_insert_with(key, Default::default)
+    }
+
+    // returns `Some(v)` iff `key` was present and associated with `v`
+    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<V>
+        where K: Borrow<Q>, C: Compare<Q> {
+        self.take(key).map(|(_, v)| v)
+    }
+
+    // Like `remove`, but hands back the key the tree stored along with the
+    // value, for keys that carry more than what they're ordered by
+    pub fn take<Q: ?Sized>(&mut self, key: &Q) -> Option<(K, V)>
+        where K: Borrow<Q>, C: Compare<Q> {
+        let removed = remove_node(&mut self.root, key, &self.cmp);
+        if removed.is_some() {
+            self.size -= 1;
+        }
+        removed
+    }
+
+    // iterator over the entries of the tree, in ascending order of key
+    pub fn iter<'a>(&'a self) -> Entries<'a, K, V> {
+        let mut entries = Entries { stack: vec!(), back: vec!(), remaining: self.size };
+        push_left_spine(&mut entries.stack, &self.root);
+        push_right_spine(&mut entries.back, &self.root);
+        entries
+    }
+
+    // iterator over the keys of the tree, in ascending order
+    pub fn keys<'a>(&'a self) -> Keys<'a, K, V> {
+        Keys { inner: self.iter() }
+    }
+
+    // iterator over the values of the tree, in ascending order of key
+    pub fn values<'a>(&'a self) -> Values<'a, K, V> {
+        Values { inner: self.iter() }
+    }
+
+    // smallest entry; the same as `first_key_value`
+    pub fn min<'a>(&'a self) -> Option<(&'a K, &'a V)> {
+        self.first_key_value()
+    }
+
+    // largest entry; the same as `last_key_value`
+    pub fn max<'a>(&'a self) -> Option<(&'a K, &'a V)> {
+        self.last_key_value()
+    }
+
+    // smallest entry, found by walking the left spine
+    pub fn first_key_value<'a>(&'a self) -> Option<(&'a K, &'a V)> {
+        let mut current: &'a Node<K, V> = match self.root {
+            Some(ref r) => &**r,
+            None => return None,
+        };
+        loop {
+            match current.left {
+                Some(ref n) => current = &**n,
+                None => return Some((&current.key, &current.value)),
+            }
+        }
+    }
+
+    // largest entry, found by walking the right spine
+    pub fn last_key_value<'a>(&'a self) -> Option<(&'a K, &'a V)> {
+        let mut current: &'a Node<K, V> = match self.root {
+            Some(ref r) => &**r,
+            None => return None,
+        };
+        loop {
+            match current.right {
+                Some(ref n) => current = &**n,
+                None => return Some((&current.key, &current.value)),
+            }
+        }
+    }
+
+    // The smallest entry, with its value mutable, as for the front of a priority
+    // queue. The key stays immutable, since changing it could break the order.
+    pub fn peek_min_mut<'a>(&'a mut self) -> Option<(&'a K, &'a mut V)> {
+        let mut current = &mut self.root;
+        loop {
+            let link = current; // move the borrow so it can be replaced below
+            match *link {
+                Some(ref mut n) => {
+                    let Node { ref key, ref mut value, ref mut left, .. } = **n;
+                    if left.is_none() {
+                        return Some((key, value));
+                    }
+                    current = left;
+                },
+                None => return None,
+            }
+        }
+    }
+
+    // the largest entry, with its value mutable, as for `peek_min_mut`
+    pub fn peek_max_mut<'a>(&'a mut self) -> Option<(&'a K, &'a mut V)> {
+        let mut current = &mut self.root;
+        loop {
+            let link = current;
+            match *link {
+                Some(ref mut n) => {
+                    let Node { ref key, ref mut value, ref mut right, .. } = **n;
+                    if right.is_none() {
+                        return Some((key, value));
+                    }
+                    current = right;
+                },
+                None => return None,
+            }
+        }
+    }
+
+    // The entry for `key`, occupied or not, for looking at or changing in place
+    // after a single search. Getting a vacant entry doesn't change the tree:
+    // the search path is only followed again to insert, if a value is given.
+    pub fn entry<'a>(&'a mut self, key: K) -> Entry<'a, K, V, C> {
+        let (path, found) = self.search_path(&key);
+        if found {
+            Entry::Occupied(OccupiedEntry { tree: self, path: path })
+        } else {
+            Entry::Vacant(VacantEntry { tree: self, key: key, path: path })
+        }
+    }
+
+    // The steps down from the root to `key`'s node, and whether it has one. If
+    // not, the steps lead to the empty link where `key` would be inserted.
+    fn search_path(&self, key: &K) -> (Vec<Step>, bool) {
+        let mut path = vec!();
+        let mut current = &self.root;
+        loop {
+            match *current {
+                Some(ref n) => match self.cmp.compare(key, &n.key) {
+                    Less => {
+                        path.push(Step::Left);
+                        current = &n.left;
+                    },
+                    Greater => {
+                        path.push(Step::Right);
+                        current = &n.right;
+                    },
+                    Equal => return (path, true)
+                },
+                None => return (path, false)
+            }
+        }
+    }
+
+    // Removes the value under `key` and returns it, but only if `pred` returns
+    // true for it; otherwise it stays. Both happen after the one search.
+    pub fn remove_if<F>(&mut self, key: &K, pred: F) -> Option<V>
+        where F: FnOnce(&V) -> bool {
+        let (path, found) = self.search_path(key);
+        if !found || !pred(&node_at(&self.root, path.as_slice()).value) {
+            return None;
+        }
+        self.size -= 1;
+        let (_, value) = remove_at(&mut self.root, path.as_slice());
+        Some(value)
+    }
+
+    // a cursor over the tree, starting at the entry with the smallest key
+    pub fn cursor_mut<'a>(&'a mut self) -> CursorMut<'a, K, V, C> {
+        CursorMut { tree: self, index: 0 }
+    }
+
+    // the entry with the smallest key, for inspecting or removing it in place
+    pub fn first_entry<'a>(&'a mut self) -> Option<OccupiedEntry<'a, K, V, C>> {
+        if self.root.is_none() {
+            return None;
+        }
+        let path = spine_path(&self.root, Step::Left);
+        Some(OccupiedEntry { tree: self, path: path })
+    }
+
+    // the entry with the largest key, as for `first_entry`
+    pub fn last_entry<'a>(&'a mut self) -> Option<OccupiedEntry<'a, K, V, C>> {
+        if self.root.is_none() {
+            return None;
+        }
+        let path = spine_path(&self.root, Step::Right);
+        Some(OccupiedEntry { tree: self, path: path })
+    }
+
+    // drops every node, without recursing (see `drop_nodes`)
+    pub fn clear(&mut self) {
+        let mut worklist = vec!();
+        match self.root.take() {
+            Some(r) => worklist.push(r),
+            None => {}
+        }
+        drop_nodes(worklist);
+        self.size = 0;
+    }
+
+    // iterator over the entries with `lo <= key < hi`, in ascending order of
+    // key. Subtrees lying wholly outside the range are never visited.
+    pub fn range<'a>(&'a self, lo: &K, hi: &K) -> RangeEntries<'a, K, V> {
+        if self.cmp.compare(lo, hi) != Less {
+            return RangeEntries { stack: vec!(), remaining: 0 };
+        }
+        // the walk stops after the keys that are at least `lo` but below `hi`
+        RangeEntries { stack: self.stack_from(lo),
+                       remaining: self.rank(hi) - self.rank(lo) }
+    }
+
+    // iterator over the entries with `lo <= key`, in ascending order of key
+    pub fn range_from<'a>(&'a self, lo: &K) -> RangeEntries<'a, K, V> {
+        RangeEntries { stack: self.stack_from(lo),
+                       remaining: self.size - self.rank(lo) }
+    }
+
+    // iterator over the entries with `key < hi`, in ascending order of key
+    pub fn range_to<'a>(&'a self, hi: &K) -> RangeEntries<'a, K, V> {
+        let mut stack = vec!();
+        push_left_spine(&mut stack, &self.root);
+        RangeEntries { stack: stack, remaining: self.rank(hi) }
+    }
+
+    // Whether any key has `lo <= key < hi`. A node below the range rules out its
+    // left subtree and one above it its right subtree, so this follows a single
+    // path down until it meets a key in the range.
+    pub fn has_any_in_range(&self, lo: &K, hi: &K) -> bool {
+        let mut current: &Link<Node<K, V>> = &self.root;
+        loop {
+            match *current {
+                Some(ref r) => {
+                    if self.cmp.compare(&r.key, lo) == Less {
+                        current = &r.right;
+                    } else if self.cmp.compare(&r.key, hi) != Less {
+                        current = &r.left;
+                    } else {
+                        return true;
+                    }
+                }
+                None => return false
+            }
+        }
+    }
+
+    // Like `iter`, but starting from the first entry with a key `>= key`, e.g.
+    // to page through a tree by resuming after the last key seen. Walking
+    // back from the other end still stops there.
+    pub fn iter_from<'a>(&'a self, key: &K) -> Entries<'a, K, V> {
+        let mut entries = Entries { stack: self.stack_from(key),
+                                    back: vec!(),
+                                    remaining: self.size - self.rank(key) };
+        push_right_spine(&mut entries.back, &self.root);
+        entries
+    }
+
+    // The traversal stack for an in-order walk starting from `lo`: the nodes
+    // on the search path for `lo` that are not below it, which are exactly
+    // what such a walk has left to visit on its way up. Subtrees to the left
+    // of that path never get pushed.
+    fn stack_from<'a>(&'a self, lo: &K) -> Vec<&'a Node<K, V>> {
+        let mut stack = vec!();
+        let mut current: &'a Link<Node<K, V>> = &self.root;
+        loop {
+            match *current {
+                Some(ref n) => {
+                    if self.cmp.compare(&n.key, lo) == Less {
+                        current = &n.right;
+                    } else {
+                        stack.push(&**n);
+                        current = &n.left;
+                    }
+                }
+                None => return stack
+            }
+        }
+    }
+
+    // largest entry with a key `<= key`
+    pub fn floor<'a>(&'a self, key: &K) -> Option<(&'a K, &'a V)> {
+        let mut best: Option<(&'a K, &'a V)> = None;
+        let mut current: &'a Link<Node<K, V>> = &self.root;
+        loop {
+            match *current {
+                Some(ref r) => {
+                    match self.cmp.compare(key, &r.key) {
+                        Less => current = &r.left,
+                        Greater => {
+                            best = Some((&r.key, &r.value));
+                            current = &r.right;
+                        },
+                        Equal => return Some((&r.key, &r.value))
+                    }
+                }
+                None => return best
+            }
+        }
+    }
+
+    // smallest entry with a key `>= key`
+    pub fn ceiling<'a>(&'a self, key: &K) -> Option<(&'a K, &'a V)> {
+        let mut best: Option<(&'a K, &'a V)> = None;
+        let mut current: &'a Link<Node<K, V>> = &self.root;
+        loop {
+            match *current {
+                Some(ref r) => {
+                    match self.cmp.compare(key, &r.key) {
+                        Less => {
+                            best = Some((&r.key, &r.value));
+                            current = &r.left;
+                        },
+                        Greater => current = &r.right,
+                        Equal => return Some((&r.key, &r.value))
+                    }
+                }
+                None => return best
+            }
+        }
+    }
+
+    // largest entry with a key strictly less than `key`
+    pub fn predecessor<'a>(&'a self, key: &K) -> Option<(&'a K, &'a V)> {
+        let mut best: Option<(&'a K, &'a V)> = None;
+        let mut current: &'a Link<Node<K, V>> = &self.root;
+        loop {
+            match *current {
+                Some(ref r) => {
+                    if self.cmp.compare(&r.key, key) == Less {
+                        best = Some((&r.key, &r.value));
+                        current = &r.right;
+                    } else {
+                        current = &r.left;
+                    }
+                }
+                None => return best
+            }
+        }
+    }
+
+    // smallest entry with a key strictly greater than `key`
+    pub fn successor<'a>(&'a self, key: &K) -> Option<(&'a K, &'a V)> {
+        let mut best: Option<(&'a K, &'a V)> = None;
+        let mut current: &'a Link<Node<K, V>> = &self.root;
+        loop {
+            match *current {
+                Some(ref r) => {
+                    if self.cmp.compare(&r.key, key) == Greater {
+                        best = Some((&r.key, &r.value));
+                        current = &r.left;
+                    } else {
+                        current = &r.right;
+                    }
+                }
+                None => return best
+            }
+        }
+    }
+
+    // `select`, but reporting an index out of range as `Err(len)`, so the
+    // caller learns the bound
+    pub fn select_or_err<'a>(&'a self, n: uint) -> Result<(&'a K, &'a V), uint> {
+        self.select(n).ok_or(self.size)
+    }
+
+    // `(predecessor(key), successor(key))`, found in a single descent. If `key`
+    // itself turns up, its neighbours are the extremes of its two subtrees,
+    // when it has them, so the search carries on down both spines from there.
+    pub fn neighbors<'a>(&'a self, key: &K)
+                         -> (Option<(&'a K, &'a V)>, Option<(&'a K, &'a V)>) {
+        let mut below: Option<(&'a K, &'a V)> = None;
+        let mut above: Option<(&'a K, &'a V)> = None;
+        let mut current: &'a Link<Node<K, V>> = &self.root;
+        loop {
+            match *current {
+                Some(ref r) => match self.cmp.compare(&r.key, key) {
+                    Less => {
+                        below = Some((&r.key, &r.value));
+                        current = &r.right;
+                    },
+                    Greater => {
+                        above = Some((&r.key, &r.value));
+                        current = &r.left;
+                    },
+                    Equal => {
+                        let mut left = &r.left;
+                        loop {
+                            match *left {
+                                Some(ref n) => {
+                                    below = Some((&n.key, &n.value));
+                                    left = &n.right;
+                                },
+                                None => break
+                            }
+                        }
+                        let mut right = &r.right;
+                        loop {
+                            match *right {
+                                Some(ref n) => {
+                                    above = Some((&n.key, &n.value));
+                                    right = &n.left;
+                                },
+                                None => break
+                            }
+                        }
+                        return (below, above);
+                    }
+                },
+                None => return (below, above)
+            }
+        }
+    }
+
+    // the entry with exactly `n` smaller keys, i.e. the nth smallest counting
+    // from 0, found by steering with the cached subtree sizes
+    pub fn select<'a>(&'a self, n: uint) -> Option<(&'a K, &'a V)> {
+        let mut n = n;
+        let mut current: &'a Link<Node<K, V>> = &self.root;
+        loop {
+            match *current {
+                Some(ref r) => {
+                    let left = subtree_size(&r.left);
+                    match n.cmp(&left) {
+                        Less => current = &r.left,
+                        Greater => {
+                            n -= left + 1;
+                            current = &r.right;
+                        },
+                        Equal => return Some((&r.key, &r.value))
+                    }
+                }
+                None => return None
+            }
+        }
+    }
+
+    // The key at the root, e.g. as a pivot for splitting work in two. The AA
+    // rules keep the tree roughly balanced, so it's likely to be near the
+    // middle, but unlike `median` it's not guaranteed to be.
+    pub fn root_key<'a>(&'a self) -> Option<&'a K> {
+        self.root.as_ref().map(|r| &r.key)
+    }
+
+    // The middle entry, at index `len / 2`. With an even number of entries
+    // that's the upper of the two in the middle.
+    pub fn median<'a>(&'a self) -> Option<(&'a K, &'a V)> {
+        self.select(self.size / 2)
+    }
+
+    // number of keys in the tree strictly less than `key`
+    pub fn rank(&self, key: &K) -> uint {
+        let mut rank = 0;
+        let mut current: &Link<Node<K, V>> = &self.root;
+        loop {
+            match *current {
+                Some(ref r) => {
+                    match self.cmp.compare(key, &r.key) {
+                        Less => current = &r.left,
+                        Greater => {
+                            rank += subtree_size(&r.left) + 1;
+                            current = &r.right;
+                        },
+                        Equal => return rank + subtree_size(&r.left)
+                    }
+                }
+                None => return rank
+            }
+        }
+    }
+
+    // Like `rank`, but only for keys in the tree: the index `key` would be
+    // found at by `select`, or `None` if it's absent
+    pub fn position_of(&self, key: &K) -> Option<uint> {
+        let mut position = 0;
+        let mut current: &Link<Node<K, V>> = &self.root;
+        loop {
+            match *current {
+                Some(ref r) => {
+                    match self.cmp.compare(key, &r.key) {
+                        Less => current = &r.left,
+                        Greater => {
+                            position += subtree_size(&r.left) + 1;
+                            current = &r.right;
+                        },
+                        Equal => return Some(position + subtree_size(&r.left))
+                    }
+                }
+                None => return None
+            }
+        }
+    }
+
+    // detaches every node from the tree, handing them to an owning iterator
+    fn take_entries(&mut self) -> IntoIter<K, V> {
+        let mut entries = IntoIter { stack: vec!(), remaining: self.size };
+        entries.push_left_spine(self.root.take());
+        self.size = 0;
+        entries
+    }
+
+    // Removes every entry for which `pred` returns false. Rather than removing
+    // the entries one by one, this takes the tree apart in order and builds a
+    // balanced tree from the survivors, which is O(n).
+    pub fn retain<F>(&mut self, mut pred: F) where F: FnMut(&K, &mut V) -> bool {
+        let mut kept = vec!();
+        for (k, mut v) in self.take_entries() {
+            if pred(&k, &mut v) {
+                kept.push((k, v));
+            }
+        }
+        self.size = kept.len();
+        self.root = build_balanced(&mut kept.into_iter(), self.size);
+    }
+
+    // Removes every entry with `lo <= key < hi`, returning how many there were.
+    // The ranks of `lo` and `hi` say where the removed entries sit in the
+    // in-order stream, so the rest are picked out without comparing keys and
+    // rebuilt into a balanced tree, in O(n) overall.
+    pub fn remove_range(&mut self, lo: &K, hi: &K) -> uint {
+        if self.cmp.compare(lo, hi) != Less {
+            return 0;
+        }
+        let below = self.rank(lo);
+        let removed = self.rank(hi) - below;
+        if removed == 0 {
+            return 0;
+        }
+
+        let mut entries = self.take_entries();
+        let mut kept: Vec<(K, V)> = entries.by_ref().take(below).collect();
+        entries.by_ref().take(removed).count();
+        kept.extend(entries);
+        self.size = kept.len();
+        self.root = build_balanced(&mut kept.into_iter(), self.size);
+        removed
+    }
+
+    // Removes whichever of `keys` are in the tree, returning how many were. A
+    // few keys are removed one at a time, in O(log n) each. For more than that,
+    // it's cheaper to sort them and take the tree apart in order, dropping the
+    // entries they name and rebuilding from the rest in O(n).
+    pub fn remove_keys(&mut self, keys: &[K]) -> uint {
+        let before = self.size;
+        if keys.len() * (max_level(self.size) + 1) < self.size {
+            for k in keys.iter() {
+                self.remove(k);
+            }
+            return before - self.size;
+        }
+
+        let mut doomed: Vec<&K> = keys.iter().collect();
+        doomed.sort_by(|a, b| self.cmp.compare(*a, *b));
+        let mut doomed = doomed.into_iter().peekable();
+        let mut kept = vec!();
+        for (k, v) in self.take_entries() {
+            // skip past the doomed keys that are smaller, or not in the tree
+            loop {
+                match doomed.peek() {
+                    Some(d) if self.cmp.compare(*d, &k) == Less => {},
+                    _ => break,
+                }
+                doomed.next();
+            }
+            let remove = match doomed.peek() {
+                Some(d) => self.cmp.compare(*d, &k) == Equal,
+                None => false,
+            };
+            if !remove {
+                kept.push((k, v));
+            }
+        }
+        self.size = kept.len();
+        self.root = build_balanced(&mut kept.into_iter(), self.size);
+        before - self.size
+    }
+
+    // Moves every entry with a key `>= key` into a new tree, which it returns.
+    // The entries stream out of the tree in order, so the first `rank(key)` of
+    // them rebuild `self` and the rest build the new tree, each balanced.
+    pub fn split_off(&mut self, key: &K) -> Tree<K, V, C> where C: Clone {
+        let lower = self.rank(key);
+        let upper = self.size - lower;
+        let mut entries = self.take_entries();
+
+        self.root = build_balanced(&mut entries, lower);
+        self.size = lower;
+        let mut other = Tree::with_comparator(self.cmp.clone());
+        other.root = build_balanced(&mut entries, upper);
+        other.size = upper;
+        other.policy = self.policy.clone();
+        other
+    }
+
+    // A tree with the same keys as this one, and the same shape, but with each
+    // value replaced by `f` of it. Copying the shape means no rebalancing.
+    pub fn map_values<W, F>(&self, mut f: F) -> Tree<K, W, C>
+        where F: FnMut(&V) -> W, K: Clone, C: Clone {
+        Tree { root: map_link(&self.root, &mut f),
+               size: self.size,
+               cmp: self.cmp.clone(),
+               policy: self.policy.clone() }
+    }
+
+    // Consumes the tree, splitting it into the entries for which `pred` returns
+    // true and those for which it returns false. The entries come out in order,
+    // so each can be pushed onto the right end of its new tree.
+    pub fn partition<F>(mut self, mut pred: F) -> (Tree<K, V, C>, Tree<K, V, C>)
+        where F: FnMut(&K, &V) -> bool, C: Clone {
+        let mut matched = Tree::with_comparator(self.cmp.clone());
+        let mut unmatched = Tree::with_comparator(self.cmp.clone());
+        matched.policy = self.policy.clone();
+        unmatched.policy = self.policy.clone();
+        for (k, v) in self.take_entries() {
+            if pred(&k, &v) {
+                matched.push_ascending(k, v);
+            } else {
+                unmatched.push_ascending(k, v);
+            }
+        }
+        (matched, unmatched)
+    }
+
+    // Moves every entry of `other` into `self`, leaving `other` empty. A key
+    // collision goes as `insert` does under the duplicate policy of `self`: the
+    // value from `other` wins under `Overwrite`, is dropped under `Keep`, and
+    // is added after the one already there under `Multi`.
+    // (Appending a tree to itself can't happen: it would need two `&mut`
+    // borrows of it.)
+    pub fn append(&mut self, other: &mut Tree<K, V, C>) {
+        for (k, v) in other.take_entries() {
+            self.insert(k, v);
+        }
+    }
+
+    // number of nodes on the longest path from the root down to a leaf (not
+    // to be confused with the AA levels)
+    pub fn height(&self) -> uint {
+        let mut height = 0;
+        let mut stack = vec!((&self.root, 1u));
+        loop {
+            match stack.pop() {
+                None => return height,
+                Some((&Some(ref n), depth)) => {
+                    height = max(height, depth);
+                    stack.push((&n.left, depth + 1));
+                    stack.push((&n.right, depth + 1));
+                },
+                Some((&None, _)) => {}
+            }
+        }
+    }
+
+    // How many nodes there are at each AA level, indexed by level (so the count
+    // at index 0 is always 0), up to the root's
+    pub fn level_histogram(&self) -> Vec<uint> {
+        let mut counts = vec!();
+        let mut stack = vec!(&self.root);
+        loop {
+            match stack.pop() {
+                None => return counts,
+                Some(&Some(ref n)) => {
+                    let level = n.level as uint;
+                    if counts.len() <= level {
+                        counts.grow(level + 1 - counts.len(), &0);
+                    }
+                    *counts.get_mut(level) += 1;
+                    stack.push(&n.left);
+                    stack.push(&n.right);
+                },
+                Some(&None) => {}
+            }
+        }
+    }
+
+    // removes and returns the smallest entry
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        if self.root.is_none() {
+            return None;
+        }
+        self.size -= 1;
+        Some(remove_min(&mut self.root))
+    }
+
+    // removes and returns the largest entry
+    pub fn pop_last(&mut self) -> Option<(K, V)> {
+        if self.root.is_none() {
+            return None;
+        }
+        self.size -= 1;
+        Some(remove_max(&mut self.root))
+    }
+
+    // Removes every entry, yielding them in ascending order of key. The tree is
+    // empty as soon as this returns, and entries the iterator doesn't get to
+    // are dropped along with it.
+    pub fn drain<'a>(&'a mut self) -> Drain<'a, K, V> {
+        Drain { inner: self.take_entries(), marker: PhantomData }
+    }
+
+    // iterator over mutable references to the values of the tree, in ascending
+    // order of key
+    pub fn values_mut<'a>(&'a mut self) -> ValuesMut<'a, K, V> {
+        let mut values = ValuesMut { stack: vec!(), remaining: self.size };
+        push_left_spine_mut(&mut values.stack, &mut self.root);
+        values
+    }
+
+    // consumes the tree, returning its entries in ascending order of key
+    pub fn into_sorted_vec(self) -> Vec<(K, V)> {
+        let mut entries = Vec::with_capacity(self.size);
+        entries.extend(self.into_iter());
+        entries
+    }
+
+    // whether any entry holds a value equal to `value`. There's no order on
+    // values to search by, so this is a linear scan.
+    pub fn contains_value(&self, value: &V) -> bool where V: PartialEq {
+        self.values().any(|v| v == value)
+    }
+
+    // All entries, sorted by value, and by key among equal values. There's no
+    // index on values, so this collects and sorts; the entries are collected in
+    // order of key, so the stable sort breaks ties by key.
+    pub fn iter_by_value<'a>(&'a self) -> Vec<(&'a K, &'a V)> where V: Ord {
+        let mut entries: Vec<(&'a K, &'a V)> = self.iter().collect();
+        entries.sort_by(|&(_, a), &(_, b)| a.cmp(b));
+        entries
+    }
+
+    // The set operations on keys walk the two trees side by side, in O(n + m).
+    // A key counts as in both trees when the comparator finds the two equal,
+    // whatever their values, and then it's `self`'s copy that is returned.
+    // `only_self`, `only_other` and `in_both` say which keys to keep.
+    fn merge_keys<'a>(&'a self, other: &'a Tree<K, V, C>,
+                      only_self: bool, only_other: bool, in_both: bool) -> Vec<&'a K> {
+        let mut keys = vec!();
+        let mut a = self.keys().peekable();
+        let mut b = other.keys().peekable();
+        loop {
+            let ord = match (a.peek(), b.peek()) {
+                (Some(x), Some(y)) => self.cmp.compare(*x, *y),
+                (Some(_), None) => Less,
+                (None, Some(_)) => Greater,
+                (None, None) => return keys,
+            };
+            match ord {
+                Less => {
+                    let k = a.next().unwrap();
+                    if only_self {
+                        keys.push(k);
+                    }
+                },
+                Greater => {
+                    let k = b.next().unwrap();
+                    if only_other {
+                        keys.push(k);
+                    }
+                },
+                Equal => {
+                    let k = a.next().unwrap();
+                    b.next();
+                    if in_both {
+                        keys.push(k);
+                    }
+                }
+            }
+        }
+    }
+
+    // the keys in both `self` and `other`, in ascending order
+    pub fn intersection<'a>(&'a self, other: &'a Tree<K, V, C>) -> Vec<&'a K> {
+        self.merge_keys(other, false, false, true)
+    }
+
+    // the keys in `self` but not in `other`, in ascending order
+    pub fn difference<'a>(&'a self, other: &'a Tree<K, V, C>) -> Vec<&'a K> {
+        self.merge_keys(other, true, false, false)
+    }
+
+    // the keys in exactly one of `self` and `other`, in ascending order
+    pub fn symmetric_difference<'a>(&'a self, other: &'a Tree<K, V, C>) -> Vec<&'a K> {
+        self.merge_keys(other, true, true, false)
+    }
+
+    // consumes the tree, yielding its keys in ascending order
+    pub fn into_keys(self) -> IntoKeys<K, V> {
+        IntoKeys { inner: self.into_iter() }
+    }
+
+    // consumes the tree, yielding its values in ascending order of key
+    pub fn into_values(self) -> IntoValues<K, V> {
+        IntoValues { inner: self.into_iter() }
+    }
+
+    // Folds `f` over the values in ascending order of key, starting from
+    // `init`, as `values().fold(init, f)` would but without the iterator's
+    // stack. The recursion only goes as deep as the tree.
+    pub fn fold_values<B, F>(&self, init: B, mut f: F) -> B where F: FnMut(B, &V) -> B {
+        fold_link(&self.root, init, &mut f)
+    }
+}
+
+// Cloning copies the structure node for node, levels and all. The recursion
+// only goes as deep as the tree, which the AA invariants keep logarithmic.
+impl<K: Clone, V: Clone, C: Clone> Clone for Tree<K, V, C> {
+    fn clone(&self) -> Tree<K, V, C> {
+        Tree { root: self.root.clone(),
+               size: self.size,
+               cmp: self.cmp.clone(),
+               policy: self.policy.clone() }
+    }
+
+    // reuses the nodes of `self` wherever they line up with nodes of `source`
+    fn clone_from(&mut self, source: &Tree<K, V, C>) {
+        clone_link_from(&mut self.root, &source.root);
+        self.size = source.size;
+        self.cmp = source.cmp.clone();
+        self.policy = source.policy.clone();
+    }
+}
+
+// Makes the subtree at `dst` a copy of the one at `src`. Where both have a
+// node in the same position, the node at `dst` is overwritten in place, so
+// only the positions missing from `dst` cost an allocation.
+fn clone_link_from<K: Clone, V: Clone>(dst: &mut Link<Node<K, V>>,
+                                       src: &Link<Node<K, V>>) {
+    match (dst.is_some(), src) {
+        (true, &Some(ref s)) => {
+            let d = dst.get_mut_ref();
+            d.key.clone_from(&s.key);
+            d.value.clone_from(&s.value);
+            d.level = s.level;
+            d.size = s.size;
+            clone_link_from(&mut d.left, &s.left);
+            clone_link_from(&mut d.right, &s.right);
+        },
+        (false, &Some(ref s)) => *dst = Some(s.clone()),
+        (_, &None) => match dst.take() {
+            Some(d) => drop_nodes(vec!(d)),
+            None => {}
+        },
+    }
+}
+
+// As with `clear`, the nodes are dropped without recursing, however deep the
+// tree is
+impl<K, V, C> Drop for Tree<K, V, C> {
+    fn drop(&mut self) {
+        match self.root.take() {
+            Some(r) => drop_nodes(vec!(r)),
+            None => {}
+        }
+    }
+}
+
+// Duplicate keys keep the last value, as with repeated `insert`s
+impl<K: Ord, V> FromIterator<(K, V)> for Tree<K, V> {
+    fn from_iter<T: Iterator<(K, V)>>(mut iter: T) -> Tree<K, V> {
+        let mut t = Tree::new();
+        t.extend(iter);
+        t
+    }
+}
+
+impl<K: Ord, V> Default for Tree<K, V> {
+    fn default() -> Tree<K, V> {
+        Tree::new()
+    }
+}
+
+impl<K, V, C: Compare<K>> Extend<(K, V)> for Tree<K, V, C> {
+    fn extend<T: Iterator<(K, V)>>(&mut self, mut iter: T) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+// Trees are equal when they hold the same entries, however differently they
+// happen to be shaped
+impl<K: PartialEq, V: PartialEq, C: Compare<K>> PartialEq for Tree<K, V, C> {
+    fn eq(&self, other: &Tree<K, V, C>) -> bool {
+        self.size == other.size
+            && self.iter().zip(other.iter()).all(|(a, b)| a == b)
+    }
+}
+
+impl<K: Eq, V: Eq, C: Compare<K>> Eq for Tree<K, V, C> {}
+
+// Hashes the length and then the entries in order of key, so trees that are
+// equal hash the same however they were built
+impl<S: Writer, K: Hash<S>, V: Hash<S>, C: Compare<K>> Hash<S> for Tree<K, V, C> {
+    fn hash(&self, state: &mut S) {
+        self.size.hash(state);
+        for (k, v) in self.iter() {
+            k.hash(state);
+            v.hash(state);
+        }
+    }
+}
+
+// `tree[key]`, which fails if `key` is absent
+impl<K, V, C: Compare<K>> Index<K, V> for Tree<K, V, C> {
+    fn index<'a>(&'a self, key: &K) -> &'a V {
+        match self.find(key) {
+            Some(v) => v,
+            None => fail!("Tree::index: key not found"),
+        }
+    }
+}
+
+impl<K, V, C: Compare<K>> IndexMut<K, V> for Tree<K, V, C> {
+    fn index_mut<'a>(&'a mut self, key: &K) -> &'a mut V {
+        match self.get_mut(key) {
+            Some(v) => v,
+            None => fail!("Tree::index_mut: key not found"),
+        }
+    }
+}
+
+// Only for debug and test builds, as a diagnosis for when `is_aa` fails
+#[cfg(any(test, debug_assertions))]
+impl<K: Show, V, C: Compare<K>> Tree<K, V, C> {
+    // Describes the first rule of `Node::is_aa` that the tree breaks, and the
+    // node (by key) that breaks it
+    pub fn check_balance(&self) -> Result<(), String> {
+        if !self.is_bst() {
+            return Err("keys out of order".to_string());
+        }
+        let mut stack = vec!(&self.root);
+        loop {
+            match stack.pop() {
+                None => return Ok(()),
+                Some(&Some(ref n)) => {
+                    match check_node_levels(&**n) {
+                        Ok(()) => {},
+                        Err(rule) => return Err(describe_level_rule(rule, &**n)),
+                    }
+                    stack.push(&n.right);
+                    stack.push(&n.left);
+                },
+                Some(&None) => {}
+            }
+        }
+    }
+}
+
+// what `n` looks like to break `rule`, for `check_balance`
+#[cfg(any(test, debug_assertions))]
+fn describe_level_rule<K: Show, V>(rule: LevelRule, n: &Node<K, V>) -> String {
+    match rule {
+        LevelRule::Leaf => format!("leaf level: leaf {} is at level {}, not 1", n.key, n.level),
+        LevelRule::Left => format!("left level: {} at level {} has a left child at level {}",
+                                   n.key, n.level, n.left.get_ref().level),
+        LevelRule::Right => format!("right level: {} at level {} has a right child at level {}",
+                                    n.key, n.level, n.right.get_ref().level),
+        LevelRule::Grandchild => format!("grandchild level: {} at level {} has a right \
+                                          grandchild at the same level", n.key, n.level),
+    }
+}
+
+// Formats as a map, `{k1: v1, k2: v2}`, in ascending order of key. See
+// `print_tree` for a view of the structure.
+impl<K: Show, V: Show, C: Compare<K>> Show for Tree<K, V, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{{"));
+        for (i, (k, v)) in self.iter().enumerate() {
+            if i != 0 {
+                try!(write!(f, ", "));
+            }
+            try!(write!(f, "{}: {}", *k, *v));
+        }
+        write!(f, "}}")
+    }
+}
+
+// In-order iterator over a tree. Rather than recursing, it keeps an explicit
+// stack of the nodes whose left subtree is being visited; the top of the stack
+// is always the next node to yield. `back` mirrors it for walking down from
+// the largest key. The two walks would go on past each other, so both stop
+// once `remaining` entries have been yielded between them.
+pub struct Entries<'a, K: 'a, V: 'a> {
+    stack: Vec<&'a Node<K, V>>,
+    back: Vec<&'a Node<K, V>>,
+    remaining: uint,
+}
+
+// Push the node at `link` and all of its left descendants onto `stack`
+fn push_left_spine<'a, K, V>(stack: &mut Vec<&'a Node<K, V>>,
+                             link: &'a Link<Node<K, V>>) {
+    let mut current = link;
+    loop {
+        match *current {
+            Some(ref n) => {
+                stack.push(&**n);
+                current = &n.left;
+            },
+            None => break,
+        }
+    }
+}
+
+// Mirror image of `push_left_spine`
+fn push_right_spine<'a, K, V>(stack: &mut Vec<&'a Node<K, V>>,
+                              link: &'a Link<Node<K, V>>) {
+    let mut current = link;
+    loop {
+        match *current {
+            Some(ref n) => {
+                stack.push(&**n);
+                current = &n.right;
+            },
+            None => break,
+        }
+    }
+}
+
+impl<'a, K, V> Iterator<(&'a K, &'a V)> for Entries<'a, K, V> {
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        if self.remaining == 0 {
+            return None;
+        }
+        match self.stack.pop() {
+            None => None,
+            Some(n) => {
+                push_left_spine(&mut self.stack, &n.right);
+                self.remaining -= 1;
+                Some((&n.key, &n.value))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator<(&'a K, &'a V)> for Entries<'a, K, V> {
+    fn next_back(&mut self) -> Option<(&'a K, &'a V)> {
+        if self.remaining == 0 {
+            return None;
+        }
+        match self.back.pop() {
+            None => None,
+            Some(n) => {
+                push_right_spine(&mut self.back, &n.left);
+                self.remaining -= 1;
+                Some((&n.key, &n.value))
+            }
+        }
+    }
+}
+
+// Owning in-order iterator. Like `Entries` it keeps an explicit stack, but
+// each node is detached from its left child as it is pushed, so popping a
+// node hands over ownership of its entry and its right subtree.
+pub struct IntoIter<K, V> {
+    stack: Vec<Box<Node<K, V>>>,
+    remaining: uint,
+}
+
+impl<K, V> IntoIter<K, V> {
+    fn push_left_spine(&mut self, link: Link<Node<K, V>>) {
+        let mut current = link;
+        loop {
+            match current {
+                Some(mut n) => {
+                    current = n.left.take();
+                    self.stack.push(n);
+                },
+                None => break,
+            }
+        }
+    }
+}
+
+// the entries not yet yielded are dropped without recursing
+impl<K, V> Drop for IntoIter<K, V> {
+    fn drop(&mut self) {
+        drop_nodes(replace(&mut self.stack, vec!()));
+    }
+}
+
+impl<K, V> Iterator<(K, V)> for IntoIter<K, V> {
+    fn next(&mut self) -> Option<(K, V)> {
+        match self.stack.pop() {
+            None => None,
+            Some(n) => {
+                let Node { key, value, right, .. } = *n;
+                self.push_left_spine(right);
+                self.remaining -= 1;
+                Some((key, value))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K, V, C> IntoIterator for Tree<K, V, C> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    // consumes the tree, yielding its entries in ascending order of key
+    fn into_iter(mut self) -> IntoIter<K, V> {
+        let mut entries = IntoIter { stack: vec!(), remaining: self.size };
+        entries.push_left_spine(self.root.take());
+        self.size = 0;
+        entries
+    }
+}
+
+// In-order iterator over a range of keys. The number of keys in the range is
+// known up front from their ranks, so the walk stops once it has yielded them.
+pub struct RangeEntries<'a, K: 'a, V: 'a> {
+    stack: Vec<&'a Node<K, V>>,
+    remaining: uint,
+}
+
+impl<'a, K, V> Iterator<(&'a K, &'a V)> for RangeEntries<'a, K, V> {
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        if self.remaining == 0 {
+            return None;
+        }
+        match self.stack.pop() {
+            None => None,
+            Some(n) => {
+                push_left_spine(&mut self.stack, &n.right);
+                self.remaining -= 1;
+                Some((&n.key, &n.value))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+// Owning iterator over a tree that it has emptied, but still borrows so the
+// tree can't be used until the iterator is gone
+pub struct Drain<'a, K: 'a, V: 'a> {
+    inner: IntoIter<K, V>,
+    marker: PhantomData<&'a mut ()>,
+}
+
+impl<'a, K, V> Iterator<(K, V)> for Drain<'a, K, V> {
+    fn next(&mut self) -> Option<(K, V)> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        self.inner.size_hint()
+    }
+}
+
+// In-order iterator over mutable references to the values. A node can't sit
+// on the stack while its left subtree is being walked, as that would alias
+// it, so nodes are split as they are pushed: the stack gets the value and the
+// right subtree, and the walk goes on into the left subtree.
+pub struct ValuesMut<'a, K: 'a, V: 'a> {
+    stack: Vec<(&'a mut V, &'a mut Link<Node<K, V>>)>,
+    remaining: uint,
+}
+
+// `push_left_spine` for `ValuesMut`'s stack
+fn push_left_spine_mut<'a, K, V>(stack: &mut Vec<(&'a mut V, &'a mut Link<Node<K, V>>)>,
+                                 link: &'a mut Link<Node<K, V>>) {
+    let mut current = link;
+    loop {
+        let link = current; // move the borrow so it can be replaced below
+        match *link {
+            Some(ref mut n) => {
+                let Node { ref mut value, ref mut left, ref mut right, .. } = **n;
+                stack.push((value, right));
+                current = left;
+            },
+            None => break,
+        }
+    }
+}
+
+impl<'a, K, V> Iterator<&'a mut V> for ValuesMut<'a, K, V> {
+    fn next(&mut self) -> Option<&'a mut V> {
+        match self.stack.pop() {
+            None => None,
+            Some((value, right)) => {
+                push_left_spine_mut(&mut self.stack, right);
+                self.remaining -= 1;
+                Some(value)
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+pub struct IntoKeys<K, V> {
+    inner: IntoIter<K, V>,
+}
+
+impl<K, V> Iterator<K> for IntoKeys<K, V> {
+    fn next(&mut self) -> Option<K> {
+        self.inner.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        self.inner.size_hint()
+    }
+}
+
+pub struct IntoValues<K, V> {
+    inner: IntoIter<K, V>,
+}
+
+impl<K, V> Iterator<V> for IntoValues<K, V> {
+    fn next(&mut self) -> Option<V> {
+        self.inner.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        self.inner.size_hint()
+    }
+}
+
+pub struct Keys<'a, K: 'a, V: 'a> {
+    inner: Entries<'a, K, V>,
+}
+
+impl<'a, K, V> Iterator<&'a K> for Keys<'a, K, V> {
+    fn next(&mut self) -> Option<&'a K> {
+        self.inner.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        self.inner.size_hint()
+    }
+}
+
+pub struct Values<'a, K: 'a, V: 'a> {
+    inner: Entries<'a, K, V>,
+}
+
+impl<'a, K, V> Iterator<&'a V> for Values<'a, K, V> {
+    fn next(&mut self) -> Option<&'a V> {
+        self.inner.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        self.inner.size_hint()
+    }
+}
+
+// The result of `Tree::entry`
+pub enum Entry<'a, K: 'a, V: 'a, C: 'a = Natural> {
+    Occupied(OccupiedEntry<'a, K, V, C>),
+    Vacant(VacantEntry<'a, K, V, C>),
+}
+
+impl<'a, K, V, C: Compare<K>> Entry<'a, K, V, C> {
+    // the key the entry is for, whether or not it's in the tree
+    pub fn key(&self) -> &K {
+        match *self {
+            Entry::Occupied(ref entry) => entry.key(),
+            Entry::Vacant(ref entry) => entry.key(),
+        }
+    }
+
+    // the entry's value, after inserting `default` if it was vacant
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        self.or_insert_with_key(|_| default)
+    }
+
+    // the entry's value, after inserting the result of `default` if it was
+    // vacant. `default` is only called if it's needed.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        self.or_insert_with_key(|_| default())
+    }
+
+    // as `or_insert_with`, but `default` is passed the key, e.g. for values
+    // that hold a copy of it
+    pub fn or_insert_with_key<F: FnOnce(&K) -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let value = default(&entry.key);
+                entry.insert(value)
+            }
+        }
+    }
+}
+
+// A key that isn't in the tree, with the path down to where it would go. The
+// tree is borrowed for as long as the entry lives, so the path stays valid.
+pub struct VacantEntry<'a, K: 'a, V: 'a, C: 'a = Natural> {
+    tree: &'a mut Tree<K, V, C>,
+    key: K,
+    path: Vec<Step>,
+}
+
+impl<'a, K, V, C: Compare<K>> VacantEntry<'a, K, V, C> {
+    // the key that `insert` would insert
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    // inserts the entry's key with `value`, returning the value now in the tree
+    pub fn insert(self, value: V) -> &'a mut V {
+        let VacantEntry { tree, key, path } = self;
+        let inserted = insert_at(&mut tree.root, path.as_slice(), key, value);
+        tree.size += 1;
+        // SAFETY: `insert_at` rebalances as `find_or_insert_node` does, by
+        // relinking boxes without moving the nodes in them, so the pointer
+        // still refers to the new value, and the tree stays borrowed for `'a`.
+        unsafe { &mut *inserted }
+    }
+}
+
+// An entry known to be in the tree. It holds on to the tree, which it can
+// remove the entry from, and to the path down to the entry's node, so none
+// of its operations need to compare keys.
+pub struct OccupiedEntry<'a, K: 'a, V: 'a, C: 'a = Natural> {
+    tree: &'a mut Tree<K, V, C>,
+    path: Vec<Step>,
+}
+
+impl<'a, K, V, C: Compare<K>> OccupiedEntry<'a, K, V, C> {
+    // the key as stored in the tree
+    pub fn key(&self) -> &K {
+        &node_at(&self.tree.root, self.path.as_slice()).key
+    }
+
+    pub fn get(&self) -> &V {
+        &node_at(&self.tree.root, self.path.as_slice()).value
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut node_at_mut(&mut self.tree.root, self.path.as_slice()).value
+    }
+
+    // the value, borrowed for as long as the tree was
+    pub fn into_mut(self) -> &'a mut V {
+        &mut node_at_mut(&mut self.tree.root, self.path.as_slice()).value
+    }
+
+    // removes the entry from the tree, returning its value
+    pub fn remove(self) -> V {
+        let (_, value) = self.remove_entry();
+        value
+    }
+
+    // removes the entry from the tree, returning its key and value
+    pub fn remove_entry(self) -> (K, V) {
+        self.tree.size -= 1;
+        remove_at(&mut self.tree.root, self.path.as_slice())
+    }
+}
+
+// A cursor for moving back and forth through the entries in order, changing
+// values or removing entries as it goes. Between the last entry and the first
+// is a "ghost" position that is on no entry, where a cursor over an empty tree
+// always is.
+//
+// Rather than a stack of nodes, the cursor only keeps its position in order.
+// Rebalancing after a removal can move any node around, but the entries that
+// are left keep their order, so the position stays meaningful. Each operation
+// finds its node again from the position and the cached subtree sizes, in
+// O(log n).
+pub struct CursorMut<'a, K: 'a, V: 'a, C: 'a = Natural> {
+    tree: &'a mut Tree<K, V, C>,
+    index: uint, // `tree.size` at the ghost position
+}
+
+impl<'a, K, V, C: Compare<K>> CursorMut<'a, K, V, C> {
+    // the position of the current entry in order, or `None` at the ghost
+    pub fn index(&self) -> Option<uint> {
+        if self.index < self.tree.size { Some(self.index) } else { None }
+    }
+
+    // the current entry, or `None` at the ghost
+    pub fn current(&mut self) -> Option<(&K, &mut V)> {
+        if self.index >= self.tree.size {
+            return None;
+        }
+        let path = path_to_index(&self.tree.root, self.index);
+        let n = node_at_mut(&mut self.tree.root, path.as_slice());
+        let Node { ref key, ref mut value, .. } = *n;
+        Some((key, value))
+    }
+
+    // moves to the next entry; from the last entry that's the ghost, and from
+    // the ghost it's the first entry
+    pub fn move_next(&mut self) {
+        self.index = if self.index >= self.tree.size { 0 } else { self.index + 1 };
+    }
+
+    // moves to the previous entry; from the first entry that's the ghost, and
+    // from the ghost it's the last entry
+    pub fn move_prev(&mut self) {
+        self.index = if self.index == 0 { self.tree.size } else { self.index - 1 };
+    }
+
+    // Removes the current entry and returns it, leaving the cursor on the entry
+    // after it (or the ghost, after the last). Does nothing at the ghost.
+    pub fn remove_current(&mut self) -> Option<(K, V)> {
+        if self.index >= self.tree.size {
+            return None;
+        }
+        let path = path_to_index(&self.tree.root, self.index);
+        self.tree.size -= 1;
+        Some(remove_at(&mut self.tree.root, path.as_slice()))
+    }
+}
+
+// A tree where each key can hold many values. Every node of the underlying
+// tree stores a `Vec` of the values under its key, in insertion order, and
+// `size` counts the values across all keys.
+pub struct MultiMap<K, V> {
+    tree: Tree<K, Vec<V>>,
+    size: uint
+}
+
+impl<K: Ord, V> MultiMap<K, V> {
+    pub fn new() -> MultiMap<K, V> {
+        MultiMap { tree: Tree::new(), size: 0 }
+    }
+
+    // total number of values, over all keys
+    pub fn len(&self) -> uint {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    fn is_aa(&self) -> bool {
+        self.tree.is_aa()
+    }
+
+    // Makes `value` the only value under `key`, returning the values it
+    // replaces (none if `key` is new). Use `append_value` to keep them.
+    pub fn insert(&mut self, key: K, value: V) -> Vec<V> {
+        let old = self.tree.get_or_insert_with(key, Vec::new);
+        let replaced = replace(old, vec!(value));
+        self.size = self.size + 1 - replaced.len();
+        replaced
+    }
+
+    // Appends `value` to the values under `key`, giving `key` a node of its own
+    // first if it's new. Nothing already under `key` is overwritten. The node
+    // is found or made in a single descent.
+    pub fn append_value(&mut self, key: K, value: V) {
+        self.tree.get_or_insert_with(key, Vec::new).push(value);
+        self.size += 1;
+    }
+
+    // all values under `key`, in insertion order
+    pub fn get_all<'a>(&'a self, key: &K) -> &'a [V] {
+        match self.tree.find(key) {
+            Some(values) => values.as_slice(),
+            None => &[],
+        }
+    }
+
+    // number of values under `key`
+    pub fn count(&self, key: &K) -> uint {
+        self.tree.find(key).map_or(0, |values| values.len())
+    }
+
+    // Removes the first value under `key` equal to `value`, returning whether
+    // there was one. A key left without values is removed from the tree.
+    pub fn remove_value(&mut self, key: &K, value: &V) -> bool where V: PartialEq {
+        let now_empty = match self.tree.get_mut(key) {
+            None => return false,
+            Some(values) => {
+                match values.iter().position(|v| v == value) {
+                    None => return false,
+                    Some(i) => {
+                        values.remove(i);
+                        values.is_empty()
+                    }
+                }
+            }
+        };
+        if now_empty {
+            self.tree.remove(key);
+        }
+        self.size -= 1;
+        true
+    }
+
+    // iterator yielding a `(key, value)` pair for every value, in ascending
+    // order of key and then in insertion order
+    pub fn iter_all<'a>(&'a self) -> AllEntries<'a, K, V> {
+        AllEntries { entries: self.tree.iter(), current: None, remaining: self.size }
+    }
+
+    // removes `key` along with all of its values, returning them
+    pub fn remove_all(&mut self, key: &K) -> Option<Vec<V>> {
+        let removed = self.tree.remove(key);
+        match removed {
+            Some(ref values) => self.size -= values.len(),
+            None => {}
+        }
+        removed
+    }
+
+    // Keeps only the values for which `pred` returns true, dropping the keys
+    // left without any. The tree is rebuilt once, as by `Tree::retain`.
+    pub fn retain_values<F>(&mut self, mut pred: F) where F: FnMut(&K, &V) -> bool {
+        let mut size = 0;
+        self.tree.retain(|k, values| {
+            values.retain(|v| pred(k, v));
+            size += values.len();
+            !values.is_empty()
+        });
+        self.size = size;
+    }
+
+    // Whether `self` and `other` have the same keys, each with the same values
+    // in any order. Unlike `==`, this ignores the order values were inserted in.
+    pub fn eq_unordered(&self, other: &MultiMap<K, V>) -> bool where V: Ord {
+        self.size == other.size
+            && self.tree.len() == other.tree.len()
+            && self.tree.iter().zip(other.tree.iter()).all(|((k1, vs1), (k2, vs2))| {
+                let mut vs1: Vec<&V> = vs1.iter().collect();
+                let mut vs2: Vec<&V> = vs2.iter().collect();
+                vs1.sort();
+                vs2.sort();
+                k1 == k2 && vs1 == vs2
+            })
+    }
+
+    // whether a value equal to `value` is held under any key; linear in `len`
+    pub fn contains_value(&self, value: &V) -> bool where V: PartialEq {
+        self.tree.values().any(|values| values.contains(value))
+    }
+}
+
+// Two multimaps are equal when they have the same keys, each with the same
+// values in the same order: the order values were inserted in is part of a
+// multimap's contents. See `eq_unordered` for comparing regardless of it.
+impl<K: Ord, V: PartialEq> PartialEq for MultiMap<K, V> {
+    fn eq(&self, other: &MultiMap<K, V>) -> bool {
+        self.size == other.size && self.tree == other.tree
+    }
+}
+
+impl<K: Ord, V: Eq> Eq for MultiMap<K, V> {}
+
+// Iterator over a multimap that flattens each key's values out into separate
+// entries
+pub struct AllEntries<'a, K: 'a, V: 'a> {
+    entries: Entries<'a, K, Vec<V>>,
+    current: Option<(&'a K, slice::Items<'a, V>)>,
+    remaining: uint,
+}
+
+impl<'a, K, V> Iterator<(&'a K, &'a V)> for AllEntries<'a, K, V> {
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        loop {
+            match self.current {
+                Some((k, ref mut values)) => {
+                    match values.next() {
+                        Some(v) => {
+                            self.remaining -= 1;
+                            return Some((k, v));
+                        },
+                        None => {}
+                    }
+                },
+                None => {}
+            }
+            match self.entries.next() {
+                Some((k, values)) => self.current = Some((k, values.iter())),
+                None => return None,
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+// Prints the subtree at `node` in pre-order, indented three spaces per level
+// of depth
+fn print_node_depth<K: Show, V: Show>(node: &Link<Node<K,V>>, depth: uint) {
+    write_node_depth(&mut io::stdout(), node, depth, None).unwrap();
+}
+
+// Writes out the subtree at `node` as `print_node_depth` does, except that
+// any subtree at `max_depth` or deeper is elided as "...". Uses an explicit
+// stack rather than recursion, and one indentation buffer that is grown and
+// shrunk as the walk moves up and down the tree.
+fn write_node_depth<K: Show, V: Show, W: Writer>(w: &mut W, node: &Link<Node<K,V>>,
+                                                 depth: uint,
+                                                 max_depth: Option<uint>) -> IoResult<()> {
+    let mut pre = String::new();
+    let mut stack = vec!((node, depth));
+    loop {
+        let (node, depth) = match stack.pop() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        while pre.len() < depth * 3 {
+            pre.push_str("   ");
+        }
+        pre.truncate(depth * 3);
+
+        match *node {
+            Some(_) if max_depth.map_or(false, |max| depth >= max) => {
+                try!(writeln!(w, "{}...", pre));
+            },
+            Some(ref n) => {
+                try!(writeln!(w, "{}{}:{}*{}", pre, n.key, n.value, n.level));
+                // right is pushed first so that the left subtree prints first
+                stack.push((&n.right, depth + 1));
+                stack.push((&n.left, depth + 1));
+            },
+            None => try!(writeln!(w, "{}-", pre)),
+        }
+    }
+}
+
+fn print_tree<K: Show, V: Show, C: Compare<K>>(tree: &Tree<K, V, C>) {
+    print_node_depth(&tree.root, 0);
+    println!("Is AA: {}", tree.is_aa());
+    println!("------------");
+}
+
+// `print_tree`, showing only the nodes less than `max_depth` below the root,
+// to keep the output for a big tree readable
+fn print_tree_capped<K: Show, V: Show, C: Compare<K>>(tree: &Tree<K, V, C>,
+                                                     max_depth: uint) {
+    write_node_depth(&mut io::stdout(), &tree.root, 0, Some(max_depth)).unwrap();
+    println!("Is AA: {}", tree.is_aa());
+    println!("------------");
+}
+
+fn main() {
+    let mut t = Tree::new();
+    print_tree(&t);
+
+    t.insert('e', 5u);
+    print_tree(&t);
+
+    t.insert('b', 88u);
+    print_tree(&t);
+
+    t.insert('d', 11u);
+    print_tree(&t);
+
+    let mut t = Tree::new();
+    t.insert(7u, ());
     t.insert(8u, ());
     t.insert(9u, ());
     t.insert(6u, ());
     assert!(t.is_aa());
 
-    print_tree(&t);
+    print_tree(&t);
+
+
+    let mut rng = rand::task_rng();
+    let between = Range::new(0u, 60);
+
+    let mut t = Tree::new();
+
+    for _ in range(0u, 22) {
+        let a = between.ind_sample(&mut rng);
+        t.insert(a, ());
+    }
+    print_tree(&t);
+
+    let a = between.ind_sample(&mut rng);
+    t.insert(a, ());
+    print_tree(&t);
+
+    println!("-------------");
+    let mut x = Tree::new();
+    x.insert(3u, ());
+    print_tree(&x);
+
+    x.insert(7u, ());
+    print_tree(&x);
+
+    x.insert(1u, ());
+    print_tree(&x);
+
+    x.insert(9u, ());
+    print_tree(&x);
+
+    x.insert(5u, ());
+    print_tree(&x);
+
+    x.insert(6u, ());
+    print_tree(&x);
+
+    x.insert(4u, ());
+    print_tree(&x);
+
+    // too big to print whole, so only its top few levels
+    let mut big = Tree::new();
+    for i in range(0u, 1000) {
+        big.insert(i, ());
+    }
+    print_tree_capped(&big, 3);
+
+}
+
+
+mod test {
+    use super::{Tree, Node, Natural, DuplicatePolicy, Entry, MultiMap, print_tree,
+                write_node_depth, skew, split, max_level};
+    use std::cell::Cell;
+    use std::cmp::{max, Ordering};
+    use std::default::Default;
+    use std::hash;
+    use std::io::MemWriter;
+    use std::mem;
+    use std::collections::TreeMap;
+    use std::rand;
+    use std::rand::{SeedableRng, XorShiftRng};
+    use std::rand::distributions::{IndependentSample, Range};
+
+    // a key whose ordering only looks at `id`, so that `tag` is a payload
+    #[deriving(Show)]
+    struct Tagged {
+        id: uint,
+        tag: char
+    }
+
+    impl PartialEq for Tagged {
+        fn eq(&self, other: &Tagged) -> bool { self.id == other.id }
+    }
+
+    impl Eq for Tagged {}
+
+    impl PartialOrd for Tagged {
+        fn partial_cmp(&self, other: &Tagged) -> Option<Ordering> { Some(self.cmp(other)) }
+    }
+
+    impl Ord for Tagged {
+        fn cmp(&self, other: &Tagged) -> Ordering { self.id.cmp(&other.id) }
+    }
+
+    #[test]
+    fn test_find() {
+        let mut t = Tree::new();
+        assert_eq!(t.find(&1u), None);
+        t.insert(1u, 'j');
+        assert_eq!(t.find(&1u), Some(&'j'));
+
+    }
+
+    // testing whether we can find all the things we inserted
+    #[test]
+    fn test_insert() {
+        let mut t: Tree<uint, u8> = Tree::new();
+        for (i, c) in range(0u, 10).zip(range(b'a', b'z')) {
+            t.insert(i, c);
+        }
+
+        for (ref i, ref c) in range(0u, 10).zip(range(b'a', b'z')) {
+            assert_eq!(t.find(i), Some(c));
+        }
+
+        assert_eq!(t.find(&10u), None);
+    }
+
+    fn insert_n_check_aa(n: uint, between: Range<uint>, rng: &mut rand::TaskRng) {
+        let mut t = Tree::new();
+
+        for _ in range(0u, n) {
+            let a = between.ind_sample(rng);
+            println!("{}", a);
+            t.insert(a, ());
+        }
+
+        assert!(t.is_aa());
+        assert!(t.verify_size());
+    }
+
+    // testing whether, after inserting 20 random keys, is_aa() returns true
+    #[test]
+    fn test_insert_is_aa() {
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 100_000);
+
+        for _ in range(0u, 300) {
+            insert_n_check_aa(20, between, &mut rng);
+        }
+    }
+
+    // testing whether, after inserting 20 random keys, is_aa() returns true,
+    // but this time some of the keys are repeated
+    #[test]
+    fn test_insert_dups_is_aa() {
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 15);
+
+        for _ in range(0u, 300) {
+            insert_n_check_aa(20, between, &mut rng);
+        }
+    }
+
+    // testing is_aa() and is_bst() as a caller outside the tree sees them,
+    // after inserts in a scattered order
+    #[test]
+    fn test_is_aa_scattered() {
+        let mut t = Tree::new();
+        for i in range(0u, 100) {
+            t.insert(i * 7 % 100, i);
+        }
+        assert!(t.is_bst());
+        assert!(t.is_aa());
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut t = Tree::new();
+        assert_eq!(t.remove(&1u), None);
+        t.insert(1u, 'a');
+        t.insert(2u, 'b');
+        t.insert(3u, 'c');
+
+        assert_eq!(t.remove(&2u), Some('b'));
+        assert_eq!(t.remove(&2u), None);
+        assert_eq!(t.size, 2);
+        assert_eq!(t.find(&1u), Some(&'a'));
+        assert_eq!(t.find(&3u), Some(&'c'));
+        assert!(t.is_aa());
+    }
+
+    // testing whether is_aa() still holds after each of a series of random
+    // removals, and whether the removed keys are really gone
+    #[test]
+    fn test_remove_is_aa() {
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 50);
+
+        for _ in range(0u, 300) {
+            let mut t = Tree::new();
+            for _ in range(0u, 30) {
+                let a = between.ind_sample(&mut rng);
+                t.insert(a, ());
+            }
+
+            for _ in range(0u, 30) {
+                let a = between.ind_sample(&mut rng);
+                t.remove(&a);
+                assert!(t.is_aa());
+                assert!(t.verify_size());
+                assert_eq!(t.find(&a), None);
+            }
+        }
+    }
+
+    // testing whether len() counts each distinct key exactly once
+    #[test]
+    fn test_len() {
+        let mut t = Tree::new();
+        assert!(t.is_empty());
+        assert_eq!(t.len(), 0);
+
+        for i in range(0u, 10) {
+            t.insert(i, 'a');
+            assert_eq!(t.len(), i + 1);
+        }
+
+        t.insert(4u, 'b');
+        assert_eq!(t.len(), 10);
+
+        t.remove(&4u);
+        assert_eq!(t.len(), 9);
+        t.remove(&4u);
+        assert_eq!(t.len(), 9);
+        assert!(!t.is_empty());
+    }
+
+    // testing whether iter() visits every key exactly once, in sorted order
+    #[test]
+    fn test_iter() {
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 1000);
+
+        let mut t = Tree::new();
+        assert_eq!(t.iter().next(), None);
+
+        for _ in range(0u, 200) {
+            let a = between.ind_sample(&mut rng);
+            t.insert(a, a * 2);
+        }
+
+        let keys: Vec<uint> = t.iter().map(|(&k, _)| k).collect();
+        assert_eq!(keys.len(), t.len());
+        for w in keys.as_slice().windows(2) {
+            assert!(w[0] < w[1]);
+        }
+        for (&k, &v) in t.iter() {
+            assert_eq!(v, k * 2);
+        }
+    }
+
+    #[test]
+    fn test_keys_values() {
+        let mut t = Tree::new();
+        for &k in [5u, 3, 9, 1, 7].iter() {
+            t.insert(k, k * 10);
+        }
+
+        assert_eq!(t.keys().count(), t.len());
+        let keys: Vec<uint> = t.keys().map(|&k| k).collect();
+        assert_eq!(keys, vec!(1u, 3, 5, 7, 9));
+        let values: Vec<uint> = t.values().map(|&v| v).collect();
+        assert_eq!(values, vec!(10u, 30, 50, 70, 90));
+    }
+
+    #[test]
+    fn test_get_mut() {
+        let mut t = Tree::new();
+        assert_eq!(t.get_mut(&1u), None);
+        for i in range(0u, 10) {
+            t.insert(i, i);
+        }
+
+        match t.get_mut(&6u) {
+            Some(v) => *v = 66,
+            None => fail!(),
+        }
+        assert_eq!(t.find(&6u), Some(&66u));
+        assert_eq!(t.find(&5u), Some(&5u));
+        assert_eq!(t.get_mut(&10u), None);
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let mut t = Tree::new();
+        assert!(!t.contains_key(&0u));
+        for i in range(0u, 20) {
+            t.insert(i * 2, ());
+        }
+
+        for i in range(0u, 20) {
+            assert!(t.contains_key(&(i * 2)));
+            assert!(!t.contains_key(&(i * 2 + 1)));
+        }
+    }
+
+    #[test]
+    fn test_multimap_get_all() {
+        let mut m = MultiMap::new();
+        assert!(m.get_all(&1u).is_empty());
+
+        m.append_value(1u, 'a');
+        m.append_value(2u, 'x');
+        m.append_value(1u, 'b');
+        m.append_value(1u, 'c');
+
+        assert_eq!(m.get_all(&1u), ['a', 'b', 'c'].as_slice());
+        assert_eq!(m.get_all(&2u), ['x'].as_slice());
+        assert!(m.get_all(&3u).is_empty());
+        assert_eq!(m.len(), 4);
+        assert!(m.is_aa());
+    }
+
+    #[test]
+    fn test_multimap_remove_all() {
+        let mut m = MultiMap::new();
+        for i in range(0u, 10) {
+            m.append_value(i, i);
+        }
+        m.append_value(4u, 40);
+        m.append_value(4u, 400);
+
+        assert_eq!(m.remove_all(&4u), Some(vec!(4u, 40, 400)));
+        assert_eq!(m.remove_all(&4u), None);
+        assert!(m.get_all(&4u).is_empty());
+        assert_eq!(m.tree.find(&4u), None);
+        assert_eq!(m.len(), 9);
+        assert!(m.is_aa());
+    }
+
+    #[test]
+    fn test_min_max() {
+        let mut t = Tree::new();
+        assert_eq!(t.min(), None);
+        assert_eq!(t.max(), None);
+
+        for &k in [50u, 20, 80, 10, 60, 90, 30].iter() {
+            t.insert(k, k + 1);
+        }
+        assert_eq!(t.min(), Some((&10u, &11u)));
+        assert_eq!(t.max(), Some((&90u, &91u)));
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut t = Tree::new();
+        for i in range(0u, 10_000) {
+            t.insert(i, i);
+        }
+
+        t.clear();
+        assert!(t.is_empty());
+        assert_eq!(t.find(&5000u), None);
+        assert_eq!(t.iter().next(), None);
+
+        t.insert(1u, 1u);
+        assert_eq!(t.len(), 1);
+        assert_eq!(t.find(&1u), Some(&1u));
+    }
+
+    #[test]
+    fn test_from_iter() {
+        let pairs = vec!((3u, 'a'), (1u, 'b'), (2u, 'c'), (3u, 'd'));
+        let t: Tree<uint, char> = pairs.into_iter().collect();
+
+        assert_eq!(t.len(), 3);
+        assert_eq!(t.find(&3u), Some(&'d'));
+        assert_eq!(t.find(&1u), Some(&'b'));
+        assert!(t.is_aa());
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut t = Tree::new();
+        t.insert(1u, 'a');
+        t.insert(2u, 'b');
+
+        t.extend(vec!((3u, 'c'), (4u, 'd'), (2u, 'e')).into_iter());
+        assert_eq!(t.len(), 4);
+        assert_eq!(t.find(&1u), Some(&'a'));
+        assert_eq!(t.find(&2u), Some(&'e'));
+        assert_eq!(t.find(&3u), Some(&'c'));
+        assert_eq!(t.find(&4u), Some(&'d'));
+        assert!(t.is_aa());
+    }
+
+    #[test]
+    fn test_into_iter() {
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 1000);
+
+        let mut t = Tree::new();
+        for _ in range(0u, 200) {
+            let a = between.ind_sample(&mut rng);
+            t.insert(a, a + 1);
+        }
+
+        let size = t.len();
+        let entries: Vec<(uint, uint)> = t.into_iter().collect();
+        assert_eq!(entries.len(), size);
+        for w in entries.as_slice().windows(2) {
+            assert!(w[0] < w[1]);
+        }
+        for &(k, v) in entries.iter() {
+            assert_eq!(v, k + 1);
+        }
+    }
+
+    #[test]
+    fn test_range_bounds() {
+        let mut t = Tree::new();
+        for i in range(0u, 10) {
+            t.insert(i * 10, i);
+        }
+
+        let keys: Vec<uint> = t.range(&20u, &50u).map(|(&k, _)| k).collect();
+        assert_eq!(keys, vec!(20u, 30, 40));
+        let keys: Vec<uint> = t.range(&15u, &51u).map(|(&k, _)| k).collect();
+        assert_eq!(keys, vec!(20u, 30, 40, 50));
+
+        assert_eq!(t.range(&30u, &30u).next(), None);
+        assert_eq!(t.range(&50u, &20u).next(), None);
+        assert_eq!(t.range(&31u, &39u).next(), None);
+        assert_eq!(t.range(&100u, &200u).next(), None);
+        assert_eq!(t.range(&0u, &1000u).count(), 10);
+    }
+
+    // testing range() against filtering a full traversal
+    #[test]
+    fn test_range_brute_force() {
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 200);
+
+        let mut t = Tree::new();
+        for _ in range(0u, 100) {
+            let a = between.ind_sample(&mut rng);
+            t.insert(a, ());
+        }
+
+        for _ in range(0u, 100) {
+            let lo = between.ind_sample(&mut rng);
+            let hi = between.ind_sample(&mut rng);
+            let expected: Vec<uint> = t.keys().map(|&k| k)
+                                       .filter(|&k| lo <= k && k < hi).collect();
+            let actual: Vec<uint> = t.range(&lo, &hi).map(|(&k, _)| k).collect();
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_floor_ceiling() {
+        let mut t = Tree::new();
+        assert_eq!(t.floor(&5u), None);
+        assert_eq!(t.ceiling(&5u), None);
+        for i in range(1u, 10) {
+            t.insert(i * 10, i);
+        }
+
+        assert_eq!(t.floor(&40u), Some((&40u, &4u)));
+        assert_eq!(t.ceiling(&40u), Some((&40u, &4u)));
+
+        assert_eq!(t.floor(&45u), Some((&40u, &4u)));
+        assert_eq!(t.ceiling(&45u), Some((&50u, &5u)));
+
+        assert_eq!(t.floor(&5u), None);
+        assert_eq!(t.ceiling(&5u), Some((&10u, &1u)));
+        assert_eq!(t.floor(&95u), Some((&90u, &9u)));
+        assert_eq!(t.ceiling(&95u), None);
+    }
+
+    // testing whether successor/predecessor chains visit the same keys as
+    // iter(), forwards and backwards
+    #[test]
+    fn test_predecessor_successor() {
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 500);
+
+        let mut t = Tree::new();
+        for _ in range(0u, 100) {
+            let a = between.ind_sample(&mut rng);
+            t.insert(a, ());
+        }
+        let keys: Vec<uint> = t.keys().map(|&k| k).collect();
+
+        let mut forward = vec!();
+        let mut next = t.min();
+        loop {
+            match next {
+                Some((&k, _)) => { forward.push(k); next = t.successor(&k); }
+                None => break,
+            }
+        }
+        assert_eq!(forward, keys);
+
+        let mut backward = vec!();
+        let mut next = t.max();
+        loop {
+            match next {
+                Some((&k, _)) => { backward.push(k); next = t.predecessor(&k); }
+                None => break,
+            }
+        }
+        backward.reverse();
+        assert_eq!(backward, keys);
+    }
+
+    #[test]
+    fn test_predecessor_successor_ends() {
+        let mut t = Tree::new();
+        for i in range(1u, 4) {
+            t.insert(i, ());
+        }
+        assert_eq!(t.predecessor(&1u), None);
+        assert_eq!(t.successor(&3u), None);
+        assert_eq!(t.predecessor(&2u), Some((&1u, &())));
+        assert_eq!(t.successor(&2u), Some((&3u, &())));
+        assert_eq!(t.successor(&0u), Some((&1u, &())));
+        assert_eq!(t.predecessor(&10u), Some((&3u, &())));
+    }
+
+    // testing whether the cached subtree sizes survive random inserts and
+    // removals
+    #[test]
+    fn test_size_consistent() {
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 60);
+
+        for _ in range(0u, 100) {
+            let mut t = Tree::new();
+            for _ in range(0u, 40) {
+                let a = between.ind_sample(&mut rng);
+                t.insert(a, ());
+                assert!(t.is_size_consistent());
+                assert!(t.verify_size());
+            }
+            for _ in range(0u, 40) {
+                let a = between.ind_sample(&mut rng);
+                t.remove(&a);
+                assert!(t.is_size_consistent());
+                assert!(t.verify_size());
+            }
+        }
+    }
+
+    #[test]
+    fn test_select_rank() {
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 1000);
+
+        let mut t = Tree::new();
+        for _ in range(0u, 200) {
+            let a = between.ind_sample(&mut rng);
+            t.insert(a, ());
+        }
+        let keys: Vec<uint> = t.keys().map(|&k| k).collect();
+
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(t.select(i), Some((k, &())));
+            assert_eq!(t.rank(k), i);
+            assert_eq!(t.rank(&(*k + 1)), i + 1);
+        }
+        assert_eq!(t.select(keys.len()), None);
+        assert_eq!(t.rank(&0u), 0);
+        assert_eq!(t.rank(&1000u), keys.len());
+    }
+
+    #[test]
+    fn test_clone() {
+        let mut t = Tree::new();
+        for i in range(0u, 100) {
+            t.insert(i, i);
+        }
+
+        let c = t.clone();
+        assert_eq!(c.root.get_ref().level, t.root.get_ref().level);
+        assert_eq!(c.root.get_ref().key, t.root.get_ref().key);
+        assert!(c.is_aa());
+        assert!(c.is_size_consistent());
+
+        t.insert(3u, 33);
+        t.insert(100u, 100);
+        t.remove(&50u);
+        assert_eq!(c.len(), 100);
+        assert_eq!(c.find(&3u), Some(&3u));
+        assert_eq!(c.find(&50u), Some(&50u));
+        assert_eq!(c.find(&100u), None);
+    }
+
+    #[test]
+    fn test_show() {
+        let mut t = Tree::new();
+        assert_eq!(format!("{}", t), "{}".to_string());
+
+        t.insert(3u, 'c');
+        t.insert(1u, 'a');
+        t.insert(2u, 'b');
+        assert_eq!(format!("{}", t), "{1: a, 2: b, 3: c}".to_string());
+    }
+
+    #[test]
+    fn test_print_tree() {
+        let mut t = Tree::new();
+        print_tree(&t);
+        for i in range(0u, 2000) {
+            t.insert(i, ());
+        }
+        print_tree(&t);
+    }
+
+    #[test]
+    fn test_borrowed_lookup() {
+        let mut t: Tree<String, uint> = Tree::new();
+        t.insert("one".to_string(), 1);
+        t.insert("two".to_string(), 2);
+        t.insert("three".to_string(), 3);
+
+        assert_eq!(t.find("two"), Some(&2u));
+        assert_eq!(t.find("four"), None);
+        assert!(t.contains_key("three"));
+        *t.get_mut("one").unwrap() = 11;
+        assert_eq!(t.find("one"), Some(&11u));
+        assert_eq!(t.remove("three"), Some(3u));
+        assert!(!t.contains_key("three"));
+    }
+
+    #[test]
+    fn test_with_comparator() {
+        let mut t = Tree::with_comparator(|a: &uint, b: &uint| b.cmp(a));
+        for i in range(0u, 20) {
+            t.insert(i, i * 2);
+        }
+
+        let keys: Vec<uint> = t.keys().map(|&k| k).collect();
+        assert_eq!(keys, range(0u, 20).rev().collect::<Vec<uint>>());
+        assert_eq!(t.find(&7u), Some(&14u));
+        assert_eq!(t.min(), Some((&19u, &38u)));
+        assert_eq!(t.remove(&7u), Some(14u));
+        assert!(t.is_aa());
+        let keys: Vec<uint> = t.range(&15u, &10u).map(|(&k, _)| k).collect();
+        assert_eq!(keys, vec!(15u, 14, 13, 12, 11));
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut t = Tree::new();
+        for i in range(0u, 100) {
+            t.insert(i, i);
+        }
+
+        t.retain(|&k, v| { *v += 1; k % 2 == 0 });
+        assert_eq!(t.len(), 50);
+        for i in range(0u, 100) {
+            if i % 2 == 0 {
+                assert_eq!(t.find(&i), Some(&(i + 1)));
+            } else {
+                assert_eq!(t.find(&i), None);
+            }
+        }
+        assert!(t.is_aa());
+        assert!(t.is_size_consistent());
+
+        t.retain(|_, _| false);
+        assert!(t.is_empty());
+        assert!(t.is_aa());
+    }
+
+    #[test]
+    fn test_split_off() {
+        let mut t = Tree::new();
+        for i in range(0u, 100) {
+            t.insert(i, i);
+        }
+
+        let upper = t.split_off(&50u);
+        assert_eq!(t.len(), 50);
+        assert_eq!(upper.len(), 50);
+        assert_eq!(t.keys().map(|&k| k).collect::<Vec<uint>>(),
+                   range(0u, 50).collect::<Vec<uint>>());
+        assert_eq!(upper.keys().map(|&k| k).collect::<Vec<uint>>(),
+                   range(50u, 100).collect::<Vec<uint>>());
+        assert!(t.is_aa() && t.is_size_consistent());
+        assert!(upper.is_aa() && upper.is_size_consistent());
+
+        let empty = t.split_off(&1000u);
+        assert!(empty.is_empty());
+        assert_eq!(t.len(), 50);
+    }
+
+    #[test]
+    fn test_append() {
+        let mut a = Tree::new();
+        let mut b = Tree::new();
+        for i in range(0u, 10) {
+            a.insert(i, 'a');
+            b.insert(i + 10, 'b');
+        }
+
+        a.append(&mut b);
+        assert!(b.is_empty());
+        assert_eq!(a.len(), 20);
+        assert_eq!(a.find(&3u), Some(&'a'));
+        assert_eq!(a.find(&13u), Some(&'b'));
+        assert!(a.is_aa());
+
+        let mut c = Tree::new();
+        for i in range(5u, 15) {
+            c.insert(i, 'c');
+        }
+        a.append(&mut c);
+        assert!(c.is_empty());
+        assert_eq!(a.len(), 20);
+        assert_eq!(a.find(&4u), Some(&'a'));
+        assert_eq!(a.find(&5u), Some(&'c'));
+        assert_eq!(a.find(&14u), Some(&'c'));
+        assert_eq!(a.find(&15u), Some(&'b'));
+        assert!(a.is_aa());
+    }
+
+    // testing whether sorted inserts keep the height within the AA bound of
+    // 2*log2(n + 1)
+    #[test]
+    fn test_height() {
+        let mut t = Tree::new();
+        assert_eq!(t.height(), 0);
+        t.insert(0u, ());
+        assert_eq!(t.height(), 1);
+
+        let mut log2 = 1u; // ceil(log2(n + 1))
+        for n in range(2u, 2000) {
+            t.insert(n - 1, ());
+            while (1u << log2) < n + 1 {
+                log2 += 1;
+            }
+            assert!(t.height() <= 2 * log2);
+        }
+    }
+
+    #[test]
+    fn test_from_sorted() {
+        for n in range(0u, 100) {
+            let pairs: Vec<(uint, uint)> = range(0u, n).map(|i| (i * 3, i)).collect();
+            let t = Tree::from_sorted(pairs.clone());
+            assert!(t.is_aa());
+            assert!(t.is_size_consistent());
+            assert_eq!(t.into_iter().collect::<Vec<(uint, uint)>>(), pairs);
+        }
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_from_sorted_dup_fails() {
+        Tree::from_sorted(vec!((1u, ()), (2u, ()), (2u, ())));
+    }
+
+    // testing insert and remove against std's TreeMap on fixed seeds, so that
+    // any failure can be reproduced
+    #[test]
+    fn test_against_treemap() {
+        let between = Range::new(0u, 200);
+        for seed in range(1u32, 20) {
+            let mut rng: XorShiftRng = SeedableRng::from_seed([seed, 2, 3, 4]);
+            let mut t = Tree::new();
+            let mut model = TreeMap::new();
+
+            for i in range(0u, 1000) {
+                let a = between.ind_sample(&mut rng);
+                if i % 3 == 0 {
+                    assert_eq!(t.remove(&a), model.pop(&a));
+                } else {
+                    assert_eq!(t.insert(a, i), model.swap(a, i));
+                }
+                assert_eq!(t.len(), model.len());
+                assert!(t.verify_size());
+            }
+
+            assert!(t.is_aa());
+            assert!(t.iter().zip(model.iter()).all(|(a, b)| a == b));
+        }
+    }
+
+    // testing the recursive insert against the raw pointer one it replaced, on
+    // the same fixed seeds: both must return the same old values and build
+    // trees of the same shape
+    #[test]
+    fn test_insert_against_raw_insert() {
+        let between = Range::new(0u, 300);
+        for seed in range(1u32, 20) {
+            let mut rng: XorShiftRng = SeedableRng::from_seed([seed, 5, 6, 7]);
+            let mut t = Tree::new();
+            let mut reference = Tree::new();
+
+            for i in range(0u, 1000) {
+                let a = between.ind_sample(&mut rng);
+                assert_eq!(t.insert(a, i), reference.insert_raw(a, i));
+                assert_eq!(t.len(), reference.len());
+                assert_eq!(t.is_aa(), reference.is_aa());
+            }
+
+            assert!(t.is_aa());
+            assert!(t == reference);
+            assert_eq!(t.root_key(), reference.root_key());
+            assert_eq!(t.level_histogram(), reference.level_histogram());
+        }
+    }
+
+    fn leaf(key: uint, level: u8) -> Box<Node<uint, ()>> {
+        let mut n = box Node::new(key, ());
+        n.level = level;
+        n
+    }
+
+    #[test]
+    fn test_skew() {
+        // no left child: nothing to do
+        let mut n = leaf(2, 1);
+        skew(&mut n);
+        assert_eq!(n.key, 2u);
+
+        // left child a level down: nothing to do
+        let mut n = leaf(2, 2);
+        n.left = Some(leaf(1, 1));
+        n.right = Some(leaf(3, 1));
+        n.size = 3;
+        skew(&mut n);
+        assert_eq!(n.key, 2u);
+
+        // horizontal left link: rotate right
+        let mut n = leaf(2, 1);
+        n.left = Some(leaf(1, 1));
+        n.size = 2;
+        skew(&mut n);
+        assert_eq!(n.key, 1u);
+        assert!(n.left.is_none());
+        assert_eq!(n.right.get_ref().key, 2u);
+        assert_eq!(n.size, 2);
+        assert_eq!(n.right.get_ref().size, 1);
+    }
+
+    #[test]
+    fn test_split() {
+        // no right child: nothing to do
+        let mut n = leaf(1, 1);
+        split(&mut n);
+        assert_eq!(n.key, 1u);
+
+        // a single horizontal right link: nothing to do
+        let mut n = leaf(1, 1);
+        n.right = Some(leaf(2, 1));
+        n.size = 2;
+        split(&mut n);
+        assert_eq!(n.key, 1u);
+        assert_eq!(n.level, 1);
+
+        // two horizontal right links: rotate left and raise the middle node
+        let mut r = leaf(2, 1);
+        r.right = Some(leaf(3, 1));
+        r.size = 2;
+        let mut n = leaf(1, 1);
+        n.right = Some(r);
+        n.size = 3;
+        split(&mut n);
+        assert_eq!(n.key, 2u);
+        assert_eq!(n.level, 2);
+        assert_eq!(n.left.get_ref().key, 1u);
+        assert_eq!(n.right.get_ref().key, 3u);
+        assert_eq!(n.size, 3);
+        assert!(n.is_aa(&Natural, false));
+    }
+
+    #[test]
+    fn test_eq() {
+        let mut a = Tree::new();
+        let mut b = Tree::new();
+        for i in range(0u, 50) {
+            a.insert(i, i);
+            b.insert(49 - i, 49 - i);
+        }
+        assert!(a == b);
+
+        b.insert(10u, 11u);
+        assert!(a != b);
+        b.insert(10u, 10u);
+        assert!(a == b);
+
+        b.remove(&0u);
+        assert!(a != b);
+        assert!(Tree::<uint, uint>::new() == Tree::new());
+    }
+
+    #[test]
+    fn test_index() {
+        let mut t = Tree::new();
+        t.insert(1u, 'a');
+        t.insert(2u, 'b');
+
+        assert_eq!(t[1u], 'a');
+        t[2u] = 'c';
+        assert_eq!(t[2u], 'c');
+        assert_eq!(t.find(&2u), Some(&'c'));
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_index_absent() {
+        let mut t = Tree::new();
+        t.insert(1u, 'a');
+        t[2u];
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_index_mut_absent() {
+        let mut t = Tree::new();
+        t.insert(1u, 'a');
+        t[2u] = 'b';
+    }
+
+    #[test]
+    fn test_get_or_insert_with() {
+        let calls = Cell::new(0u);
+        let mut t = Tree::new();
+
+        *t.get_or_insert_with(1u, || { calls.set(calls.get() + 1); 10u }) += 1;
+        *t.get_or_insert_with(1u, || { calls.set(calls.get() + 1); 20u }) += 1;
+        assert_eq!(calls.get(), 1);
+        assert_eq!(t.find(&1u), Some(&12u));
+        assert_eq!(t.len(), 1);
+
+        for i in range(0u, 100) {
+            *t.get_or_insert_with(i % 10, || 0) += 1;
+        }
+        assert_eq!(t.len(), 10);
+        assert_eq!(t.find(&1u), Some(&22u));
+        assert_eq!(t.find(&2u), Some(&10u));
+        assert!(t.is_aa());
+        assert!(t.is_size_consistent());
+    }
+
+    #[test]
+    fn test_update() {
+        let mut t = Tree::new();
+        t.insert(1u, 10u);
+
+        assert!(t.update(&1u, |v| *v += 5));
+        assert_eq!(t.find(&1u), Some(&15u));
+        assert!(!t.update(&2u, |v| *v += 5));
+        assert_eq!(t.find(&2u), None);
+        assert_eq!(t.len(), 1);
+    }
+
+    #[test]
+    fn test_multimap_count() {
+        let mut m = MultiMap::new();
+        for &(k, v) in [(1u, 'a'), (2, 'b'), (1, 'c'), (2, 'd'), (1, 'e')].iter() {
+            m.append_value(k, v);
+        }
+        assert_eq!(m.count(&1u), 3);
+        assert_eq!(m.count(&2u), 2);
+        assert_eq!(m.count(&3u), 0);
+    }
+
+    #[test]
+    fn test_multimap_remove_value() {
+        let mut m = MultiMap::new();
+        for i in range(0u, 10) {
+            m.append_value(i, 'x');
+        }
+        m.append_value(5u, 'a');
+        m.append_value(5u, 'b');
+        m.append_value(5u, 'c');
+
+        assert!(m.remove_value(&5u, &'b'));
+        assert_eq!(m.get_all(&5u), ['x', 'a', 'c'].as_slice());
+        assert!(!m.remove_value(&5u, &'b'));
+        assert!(!m.remove_value(&11u, &'x'));
+        assert_eq!(m.len(), 12);
+
+        assert!(m.remove_value(&3u, &'x'));
+        assert_eq!(m.tree.find(&3u), None);
+        assert_eq!(m.len(), 11);
+        assert!(m.is_aa());
+    }
+
+    #[test]
+    fn test_multimap_iter_all() {
+        let mut m = MultiMap::new();
+        for &(k, v) in [(2u, 'a'), (1, 'b'), (2, 'c'), (3, 'd'), (2, 'e')].iter() {
+            m.append_value(k, v);
+        }
+
+        let all: Vec<(uint, char)> = m.iter_all().map(|(&k, &v)| (k, v)).collect();
+        assert_eq!(all.len(), m.len());
+        assert_eq!(all, vec!((1u, 'b'), (2, 'a'), (2, 'c'), (2, 'e'), (3, 'd')));
+        for w in all.as_slice().windows(2) {
+            let (a, _) = w[0];
+            let (b, _) = w[1];
+            assert!(a <= b);
+        }
+    }
+
+    #[test]
+    fn test_first_last_key_value() {
+        let mut t = Tree::new();
+        assert_eq!(t.first_key_value(), None);
+        assert_eq!(t.last_key_value(), None);
+
+        for &k in [50u, 20, 80, 10, 60, 90, 30].iter() {
+            t.insert(k, ());
+        }
+        assert_eq!(t.first_key_value(), t.iter().next());
+        assert_eq!(t.last_key_value(), t.iter().last());
+        assert_eq!(t.first_key_value(), t.min());
+        assert_eq!(t.last_key_value(), t.max());
+    }
+
+    #[test]
+    fn test_pop_first_last() {
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 1000);
+
+        let mut t = Tree::new();
+        assert_eq!(t.pop_first(), None);
+        assert_eq!(t.pop_last(), None);
+        for _ in range(0u, 200) {
+            let a = between.ind_sample(&mut rng);
+            t.insert(a, a);
+        }
+        let keys: Vec<uint> = t.keys().map(|&k| k).collect();
+
+        let mut popped = vec!();
+        loop {
+            match t.pop_first() {
+                Some((k, v)) => {
+                    assert_eq!(k, v);
+                    popped.push(k);
+                },
+                None => break,
+            }
+            assert!(t.is_aa());
+            assert!(t.is_size_consistent());
+        }
+        assert_eq!(popped, keys);
+        assert!(t.is_empty());
+
+        for &k in keys.iter() {
+            t.insert(k, k);
+        }
+        let mut popped = vec!();
+        loop {
+            match t.pop_last() {
+                Some((k, _)) => popped.push(k),
+                None => break,
+            }
+            assert!(t.is_aa());
+            assert!(t.is_size_consistent());
+        }
+        popped.reverse();
+        assert_eq!(popped, keys);
+    }
+
+    // testing whether overwriting a key leaves `size` and the stored key alone
+    #[test]
+    fn test_insert_overwrite() {
+        let mut t = Tree::new();
+        assert_eq!(t.insert(Tagged { id: 1, tag: 'a' }, 1u), None);
+        assert_eq!(t.insert(Tagged { id: 2, tag: 'a' }, 2u), None);
+        assert_eq!(t.len(), 2);
+
+        assert_eq!(t.insert(Tagged { id: 1, tag: 'b' }, 10u), Some(1u));
+        assert_eq!(t.len(), 2);
+        assert_eq!(t.size, 2);
+        assert!(t.is_size_consistent());
+        assert_eq!(t.find(&Tagged { id: 1, tag: 'z' }), Some(&10u));
+        let tags: Vec<char> = t.keys().map(|k| k.tag).collect();
+        assert_eq!(tags, vec!('a', 'a'));
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut t = Tree::new();
+        for i in range(0u, 100) {
+            t.insert(i, i);
+        }
+
+        {
+            let mut drain = t.drain();
+            let firsts: Vec<(uint, uint)> = drain.by_ref().take(50).collect();
+            assert_eq!(firsts, range(0u, 50).map(|i| (i, i)).collect::<Vec<(uint, uint)>>());
+        }
+        assert!(t.is_empty());
+        assert_eq!(t.iter().next(), None);
+        assert_eq!(t.find(&75u), None);
+
+        t.insert(1u, 1u);
+        assert_eq!(t.drain().collect::<Vec<(uint, uint)>>(), vec!((1u, 1u)));
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn test_get_key_value() {
+        let mut t = Tree::new();
+        t.insert(Tagged { id: 1, tag: 'a' }, 'x');
+        t.insert(Tagged { id: 2, tag: 'b' }, 'y');
+
+        match t.get_key_value(&Tagged { id: 2, tag: '?' }) {
+            Some((k, v)) => {
+                assert_eq!(k.tag, 'b');
+                assert_eq!(*v, 'y');
+            },
+            None => fail!(),
+        }
+        assert!(t.get_key_value(&Tagged { id: 3, tag: 'b' }).is_none());
+    }
+
+    // testing range_from() and range_to() against filtering a full traversal,
+    // at keys both present and absent
+    #[test]
+    fn test_range_from_to() {
+        let mut t = Tree::new();
+        for i in range(0u, 50) {
+            t.insert(i * 2, ());
+        }
+
+        for bound in range(0u, 102) {
+            let expected: Vec<uint> = t.keys().map(|&k| k).filter(|&k| k >= bound).collect();
+            let actual: Vec<uint> = t.range_from(&bound).map(|(&k, _)| k).collect();
+            assert_eq!(actual, expected);
+
+            let expected: Vec<uint> = t.keys().map(|&k| k).filter(|&k| k < bound).collect();
+            let actual: Vec<uint> = t.range_to(&bound).map(|(&k, _)| k).collect();
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_values_mut() {
+        let mut t = Tree::new();
+        for i in range(0u, 100) {
+            t.insert(i, i);
+        }
+
+        for v in t.values_mut() {
+            *v *= 2;
+        }
+        for i in range(0u, 100) {
+            assert_eq!(t.find(&i), Some(&(i * 2)));
+        }
+        assert_eq!(t.values_mut().count(), 100);
+    }
+
+    #[test]
+    fn test_into_sorted_vec() {
+        let mut t = Tree::new();
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 1000);
+        for _ in range(0u, 200) {
+            let k = between.ind_sample(&mut rng);
+            t.insert(k, k * 2);
+        }
+
+        let size = t.len();
+        let entries = t.into_sorted_vec();
+        assert_eq!(entries.len(), size);
+        assert!(entries.as_slice().windows(2).all(|w| {
+            let (a, _) = w[0];
+            let (b, _) = w[1];
+            a < b
+        }));
+        assert!(entries.iter().all(|&(k, v)| v == k * 2));
+    }
+
+    #[test]
+    fn test_default() {
+        let mut t: Tree<uint, char> = Default::default();
+        assert!(t.is_empty());
+        t.insert(1, 'a');
+        t.insert(0, 'b');
+        assert_eq!(t.len(), 2);
+        assert_eq!(t.find(&1), Some(&'a'));
+        assert!(t.is_aa());
+    }
+
+    #[test]
+    fn test_iter_rev() {
+        let mut t = Tree::new();
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 1000);
+        for _ in range(0u, 300) {
+            let k = between.ind_sample(&mut rng);
+            t.insert(k, k);
+        }
+
+        let mut forward: Vec<(&uint, &uint)> = t.iter().collect();
+        let backward: Vec<(&uint, &uint)> = t.iter().rev().collect();
+        forward.reverse();
+        assert_eq!(backward, forward);
+    }
+
+    #[test]
+    fn test_iter_next_and_next_back() {
+        let mut t = Tree::new();
+        for i in range(0u, 7) {
+            t.insert(i, ());
+        }
+
+        let mut it = t.iter();
+        assert_eq!(it.next().map(|(&k, _)| k), Some(0));
+        assert_eq!(it.next_back().map(|(&k, _)| k), Some(6));
+        assert_eq!(it.next_back().map(|(&k, _)| k), Some(5));
+        assert_eq!(it.next().map(|(&k, _)| k), Some(1));
+        assert_eq!(it.size_hint(), (3, Some(3)));
+        assert_eq!(it.next().map(|(&k, _)| k), Some(2));
+        assert_eq!(it.next_back().map(|(&k, _)| k), Some(4));
+        assert_eq!(it.next().map(|(&k, _)| k), Some(3));
+        assert_eq!(it.next_back(), None);
+        assert_eq!(it.next(), None);
+
+        let empty: Tree<uint, ()> = Tree::new();
+        assert_eq!(empty.iter().next_back(), None);
+    }
+
+    #[test]
+    fn test_contains_value() {
+        let mut t = Tree::new();
+        for i in range(0u, 50) {
+            t.insert(i, i * 3);
+        }
+        assert!(t.contains_value(&0));
+        assert!(t.contains_value(&147));
+        assert!(!t.contains_value(&4));
+        assert!(!t.contains_value(&150));
+
+        let empty: Tree<uint, uint> = Tree::new();
+        assert!(!empty.contains_value(&0));
+    }
+
+    #[test]
+    fn test_multimap_contains_value() {
+        let mut m = MultiMap::new();
+        m.append_value(1u, 'a');
+        m.append_value(1u, 'b');
+        m.append_value(2u, 'c');
+        assert!(m.contains_value(&'a'));
+        assert!(m.contains_value(&'b'));
+        assert!(m.contains_value(&'c'));
+        assert!(!m.contains_value(&'d'));
+
+        m.remove_value(&1, &'b');
+        assert!(!m.contains_value(&'b'));
+    }
+
+    #[test]
+    fn test_level_fits_in_a_byte() {
+        // key, value and level share the first word, then the two links and
+        // the size take one each
+        assert_eq!(mem::size_of::<Node<u8, u8>>(), 4 * mem::size_of::<uint>());
+
+        let mut t = Tree::new();
+        for i in range(0u, 100_000) {
+            t.insert(i, ());
+        }
+        assert!(t.is_aa());
+        assert!(t.root.get_ref().level <= 17);
+    }
+
+    #[test]
+    fn test_get_many_mut() {
+        let mut t = Tree::new();
+        for i in range(0u, 100) {
+            t.insert(i, i);
+        }
+
+        {
+            let values = t.get_many_mut(&[&70, &3, &41]).unwrap();
+            assert_eq!(values.len(), 3);
+            let mut values = values.into_iter();
+            let a = values.next().unwrap();
+            let b = values.next().unwrap();
+            let c = values.next().unwrap();
+            assert_eq!((*a, *b, *c), (70, 3, 41));
+            *a += *b;
+            *c = 0;
+        }
+        assert_eq!(t.find(&70), Some(&73));
+        assert_eq!(t.find(&3), Some(&3));
+        assert_eq!(t.find(&41), Some(&0));
+
+        assert_eq!(t.get_many_mut(&[]).map(|v| v.len()), Some(0));
+    }
+
+    #[test]
+    fn test_get_pair_mut() {
+        let mut t = Tree::new();
+        for i in range(0u, 100) {
+            t.insert(i, 100u);
+        }
+
+        // transfer between two keys, on either side of the root and on one side
+        for &(from, to) in [(10u, 90u), (90, 10), (3, 5), (62, 99)].iter() {
+            match t.get_pair_mut(&from, &to) {
+                (Some(a), Some(b)) => {
+                    *a -= 30;
+                    *b += 30;
+                },
+                _ => fail!("missing key"),
+            }
+        }
+        assert_eq!(t.find(&10), Some(&100));
+        assert_eq!(t.find(&90), Some(&100));
+        assert_eq!(t.find(&3), Some(&70));
+        assert_eq!(t.find(&5), Some(&130));
+        assert_eq!(t.find(&62), Some(&70));
+        assert_eq!(t.find(&99), Some(&130));
+
+        // the root paired with a key below it
+        let root = *t.root_key().unwrap();
+        match t.get_pair_mut(&0, &root) {
+            (Some(a), Some(b)) => {
+                *a = 1;
+                *b = 2;
+            },
+            _ => fail!("missing key"),
+        }
+        assert_eq!(t.find(&0), Some(&1));
+        assert_eq!(t.find(&root), Some(&2));
+
+        let (a, b) = t.get_pair_mut(&200, &4);
+        assert!(a.is_none());
+        assert_eq!(b.map(|v| *v), Some(100));
+        let (a, b) = t.get_pair_mut(&4, &200);
+        assert_eq!(a.map(|v| *v), Some(100));
+        assert!(b.is_none());
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_get_pair_mut_same_key() {
+        let mut t = Tree::new();
+        t.insert(1u, 1u);
+        t.get_pair_mut(&1, &1);
+    }
+
+    #[test]
+    fn test_get_many_mut_duplicate_key() {
+        let mut t = Tree::new();
+        for i in range(0u, 10) {
+            t.insert(i, i);
+        }
+        assert!(t.get_many_mut(&[&1, &5, &1]).is_none());
+    }
+
+    #[test]
+    fn test_get_many_mut_missing_key() {
+        let mut t = Tree::new();
+        for i in range(0u, 10) {
+            t.insert(i * 2, i);
+        }
+        assert!(t.get_many_mut(&[&4, &5]).is_none());
+        assert!(t.get_many_mut(&[&4, &100]).is_none());
+    }
+
+    #[test]
+    fn test_is_bst_corrupted() {
+        // 7 is in the left subtree of 5: each parent/child pair is in order,
+        // but the in-order sequence 3, 7, 5 is not
+        let mut l = leaf(3, 1);
+        l.right = Some(leaf(7, 1));
+        l.size = 2;
+        let mut n = leaf(5, 2);
+        n.left = Some(l);
+        n.size = 3;
+        let mut t = Tree::new();
+        t.root = Some(n);
+        t.size = 3;
+        assert!(!t.is_bst());
+        assert!(!t.is_aa());
+
+        // a duplicate key breaks the strict order
+        let mut t = Tree::new();
+        for i in range(0u, 20) {
+            t.insert(i, ());
+        }
+        assert!(t.is_bst());
+        let left_key = t.root.get_ref().left.get_ref().key;
+        t.root.get_mut_ref().key = left_key;
+        assert!(!t.is_bst());
+    }
+
+    #[test]
+    fn test_entry_or_default() {
+        let mut counts = Tree::new();
+        for &c in ['b', 'a', 'c', 'a', 'b', 'a'].iter() {
+            *counts.entry_or_default(c) += 1u;
+        }
+
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts.find(&'a'), Some(&3));
+        assert_eq!(counts.find(&'b'), Some(&2));
+        assert_eq!(counts.find(&'c'), Some(&1));
+        assert!(counts.is_aa());
+    }
+
+    #[test]
+    fn test_hash() {
+        let mut a = Tree::new();
+        let mut b = Tree::new();
+        for i in range(0u, 100) {
+            a.insert(i, i * 2);
+            b.insert(99 - i, (99 - i) * 2);
+        }
+        assert_eq!(hash::hash(&a), hash::hash(&b));
+
+        b.insert(50, 0);
+        assert!(hash::hash(&a) != hash::hash(&b));
+    }
+
+    #[test]
+    fn test_push_ascending() {
+        let mut pushed = Tree::new();
+        let mut inserted = Tree::new();
+        for i in range(0u, 10000) {
+            pushed.push_ascending(i, i);
+            inserted.insert(i, i);
+        }
+
+        assert!(pushed.is_aa());
+        assert!(pushed.is_size_consistent());
+        assert_eq!(pushed.len(), 10000);
+        assert!(pushed == inserted);
+    }
+
+    #[test]
+    #[should_fail]
+    fn test_push_ascending_out_of_order() {
+        let mut t = Tree::new();
+        t.push_ascending(2u, ());
+        t.push_ascending(1u, ());
+    }
+
+    #[test]
+    fn test_drop_deep_tree() {
+        // a 100k node chain of right children, far deeper than any AA tree,
+        // which recursive `Box` drops would need a frame per node to free
+        let mut root = None;
+        for i in range(0u, 100_000).rev() {
+            let mut n = leaf(i, 1);
+            n.size = 100_000 - i;
+            n.right = root;
+            root = Some(n);
+        }
+        let mut t = Tree::new();
+        t.root = root;
+        t.size = 100_000;
+        assert_eq!(t.len(), 100_000);
+        drop(t);
+    }
+
+    #[test]
+    fn test_partition() {
+        let mut t = Tree::new();
+        for i in range(0u, 100) {
+            t.insert(i, i * 10);
+        }
+
+        let (even, odd) = t.partition(|&k, _| k % 2 == 0);
+        assert!(even.is_aa() && even.is_size_consistent());
+        assert!(odd.is_aa() && odd.is_size_consistent());
+        assert_eq!(even.iter().map(|(&k, &v)| (k, v)).collect::<Vec<(uint, uint)>>(),
+                   range(0u, 50).map(|i| (2 * i, 20 * i)).collect::<Vec<(uint, uint)>>());
+        assert_eq!(odd.keys().map(|&k| k).collect::<Vec<uint>>(),
+                   range(0u, 50).map(|i| 2 * i + 1).collect::<Vec<uint>>());
+    }
+
+    #[test]
+    fn test_clone_from() {
+        let mut source = Tree::new();
+        for i in range(0u, 100) {
+            source.insert(i * 3, i);
+        }
+        let mut t = Tree::new();
+        for i in range(0u, 60) {
+            t.insert(i, i + 1000);
+        }
+
+        t.clone_from(&source);
+        assert!(t == source);
+        assert!(t.is_aa() && t.is_size_consistent());
+
+        source.insert(1, 1);
+        source.remove(&0);
+        assert_eq!(t.len(), 100);
+        assert_eq!(t.find(&0), Some(&0));
+        assert_eq!(t.find(&1), None);
+
+        // a bigger source than the tree being overwritten
+        let mut small = Tree::new();
+        small.insert(5u, 5u);
+        small.clone_from(&source);
+        assert!(small == source);
+        assert!(small.is_aa() && small.is_size_consistent());
+    }
+
+    #[test]
+    fn test_neighbors() {
+        let mut t = Tree::new();
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 500);
+        for _ in range(0u, 150) {
+            let k = between.ind_sample(&mut rng);
+            t.insert(k, k);
+        }
+
+        for k in range(0u, 510) {
+            assert_eq!(t.neighbors(&k), (t.predecessor(&k), t.successor(&k)));
+        }
+
+        let empty: Tree<uint, uint> = Tree::new();
+        assert_eq!(empty.neighbors(&3), (None, None));
+    }
+
+    #[test]
+    fn test_position_of() {
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 1000);
+
+        let mut t = Tree::new();
+        for _ in range(0u, 200) {
+            let a = between.ind_sample(&mut rng);
+            t.insert(a * 2, ());
+        }
+
+        for k in range(0u, 2000) {
+            match t.position_of(&k) {
+                Some(i) => {
+                    assert_eq!(t.select(i), Some((&k, &())));
+                    assert_eq!(i, t.rank(&k));
+                },
+                None => assert!(!t.contains_key(&k)),
+            }
+        }
+        assert_eq!(t.position_of(&1), None);
+    }
+
+    fn assert_send<T: Send>() {}
+    fn assert_sync<T: Sync>() {}
+
+    #[test]
+    fn test_send_sync() {
+        // no field holds a raw pointer (the one in `get_or_insert_with` never
+        // outlives the call), so these follow from `K` and `V`
+        assert_send::<Tree<int, int>>();
+        assert_sync::<Tree<int, int>>();
+        assert_send::<MultiMap<int, int>>();
+        assert_sync::<MultiMap<int, int>>();
+    }
+
+    #[test]
+    fn test_iter_from() {
+        let mut t = Tree::new();
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 1000);
+        for _ in range(0u, 200) {
+            let k = between.ind_sample(&mut rng);
+            t.insert(k, ());
+        }
+
+        for k in range(0u, 1010) {
+            let from: Vec<uint> = t.iter_from(&k).map(|(&k, _)| k).collect();
+            let expected: Vec<uint> = t.keys().map(|&k| k).filter(|&j| j >= k).collect();
+            assert_eq!(from.as_slice().head(), t.ceiling(&k).map(|(k, _)| k));
+            assert_eq!(from, expected);
+            assert_eq!(t.iter_from(&k).rev().count(), expected.len());
+        }
+
+        // paging through in chunks of 10
+        let mut pages = vec!();
+        let mut next = 0u;
+        loop {
+            let page: Vec<uint> = t.iter_from(&next).take(10).map(|(&k, _)| k).collect();
+            match page.last() {
+                Some(&last) => next = last + 1,
+                None => break
+            }
+            pages.push_all(page.as_slice());
+        }
+        assert_eq!(pages, t.keys().map(|&k| k).collect::<Vec<uint>>());
+    }
+
+    #[test]
+    fn test_remove_range() {
+        let mut t = Tree::new();
+        for i in range(0u, 99) {
+            t.insert(i, i);
+        }
+
+        assert_eq!(t.remove_range(&33, &66), 33);
+        assert!(t.is_aa() && t.is_size_consistent());
+        assert_eq!(t.len(), 66);
+        assert_eq!(t.keys().map(|&k| k).collect::<Vec<uint>>(),
+                   range(0u, 33).chain(range(66u, 99)).collect::<Vec<uint>>());
+
+        assert_eq!(t.remove_range(&40, &50), 0);
+        assert_eq!(t.remove_range(&70, &70), 0);
+        assert_eq!(t.remove_range(&80, &10), 0);
+        assert_eq!(t.len(), 66);
+
+        assert_eq!(t.remove_range(&0, &1000), 66);
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn test_max_level() {
+        assert_eq!(max_level(0), 0);
+        assert_eq!(max_level(1), 1);
+        assert_eq!(max_level(2), 1);
+        assert_eq!(max_level(3), 2);
+        assert_eq!(max_level(6), 2);
+        assert_eq!(max_level(7), 3);
+
+        // a sorted run keeps splitting its way up the right spine
+        let mut t = Tree::new();
+        for i in range(0u, 5000) {
+            t.insert(i, ());
+            assert!(t.root.get_ref().level as uint <= max_level(t.len()));
+        }
+        assert!(t.is_aa());
+    }
+
+    #[test]
+    fn test_map_values() {
+        let mut t = Tree::new();
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 1000);
+        for _ in range(0u, 200) {
+            let k = between.ind_sample(&mut rng);
+            t.insert(k, k + 1);
+        }
+
+        let mut seen = vec!();
+        let strings = t.map_values(|v| {
+            seen.push(*v);
+            v.to_string()
+        });
+        assert!(strings.is_aa() && strings.is_size_consistent());
+        assert_eq!(strings.len(), t.len());
+        assert_eq!(strings.height(), t.height());
+        assert!(strings.iter().zip(t.iter()).all(|((k1, s), (k2, v))| {
+            k1 == k2 && *s == v.to_string()
+        }));
+        assert_eq!(seen, t.values().map(|&v| v).collect::<Vec<uint>>());
+    }
+
+    #[test]
+    fn test_try_insert() {
+        let mut t = Tree::new();
+        match t.try_insert(1u, 'a') {
+            Ok(v) => {
+                assert_eq!(*v, 'a');
+                *v = 'b';
+            },
+            Err(_) => fail!("try_insert: rejected a new key"),
+        }
+        assert_eq!(t.find(&1), Some(&'b'));
+        assert_eq!(t.len(), 1);
+
+        assert_eq!(t.try_insert(1u, 'c'), Err((1u, 'c')));
+        assert_eq!(t.find(&1), Some(&'b'));
+        assert_eq!(t.len(), 1);
+
+        for i in range(2u, 100) {
+            assert!(t.try_insert(i, 'x').is_ok());
+        }
+        assert!(t.is_aa() && t.is_size_consistent());
+    }
+
+    #[test]
+    fn test_iter_by_value() {
+        let mut t = Tree::new();
+        let mut rng = rand::task_rng();
+        let keys = Range::new(0u, 1000);
+        let values = Range::new(0u, 20);
+        for _ in range(0u, 200) {
+            t.insert(keys.ind_sample(&mut rng), values.ind_sample(&mut rng));
+        }
+
+        let by_value = t.iter_by_value();
+        assert_eq!(by_value.len(), t.len());
+        assert!(by_value.as_slice().windows(2).all(|w| {
+            let (k1, v1) = w[0];
+            let (k2, v2) = w[1];
+            v1 < v2 || (v1 == v2 && k1 < k2)
+        }));
+    }
+
+    #[test]
+    fn test_level_histogram() {
+        let empty: Tree<uint, ()> = Tree::new();
+        assert_eq!(empty.level_histogram(), vec!());
+
+        // inserting 0..7 in order ends up as
+        //         3
+        //      /     \
+        //     1       5
+        //    / \     / \
+        //   0   2   4   6
+        // with 3 at level 3, 1 and 5 at level 2 and the rest at level 1
+        let mut t = Tree::new();
+        for i in range(0u, 7) {
+            t.insert(i, ());
+        }
+        assert_eq!(t.level_histogram(), vec!(0, 4, 2, 1));
+    }
+
+    #[test]
+    fn test_insert_many() {
+        let mut t = Tree::new();
+        for i in range(0u, 10) {
+            t.insert(i * 10, i);
+        }
+
+        let batch = vec!((5u, 100u), (30, 101), (55, 102), (90, 103), (5, 104));
+        let overwritten = t.insert_many(batch.into_iter());
+        assert_eq!(overwritten, vec!((30u, 3u), (90, 9), (5, 100)));
+        assert_eq!(t.len(), 12);
+        assert_eq!(t.find(&30), Some(&101));
+        assert_eq!(t.find(&5), Some(&104));
+        assert!(t.is_aa() && t.is_size_consistent());
+
+        assert_eq!(t.insert_many(range(200u, 210).map(|i| (i, i))), vec!());
+    }
+
+    #[test]
+    fn test_peek_min_max_mut() {
+        let mut t: Tree<uint, uint> = Tree::new();
+        assert!(t.peek_min_mut().is_none());
+        assert!(t.peek_max_mut().is_none());
+
+        for i in range(10u, 50) {
+            t.insert(i, 0);
+        }
+        {
+            let (k, v) = t.peek_min_mut().unwrap();
+            assert_eq!(*k, 10);
+            *v = 7;
+        }
+        {
+            let (k, v) = t.peek_max_mut().unwrap();
+            assert_eq!(*k, 49);
+            *v += 3;
+        }
+        assert_eq!(t.first_key_value(), Some((&10, &7)));
+        assert_eq!(t.last_key_value(), Some((&49, &3)));
+        assert!(t.is_aa());
+    }
+
+    #[test]
+    fn test_has_any_in_range() {
+        let mut t = Tree::new();
+        for i in range(0u, 50) {
+            t.insert(i * 4, ());
+        }
+
+        // empty ranges
+        assert!(!t.has_any_in_range(&8, &8));
+        assert!(!t.has_any_in_range(&12, &8));
+        assert!(!t.has_any_in_range(&9, &12));
+        assert!(!t.has_any_in_range(&197, &1000));
+
+        // a single hit, at either end
+        assert!(t.has_any_in_range(&9, &13));
+        assert!(t.has_any_in_range(&12, &13));
+        assert!(t.has_any_in_range(&196, &1000));
+        assert!(t.has_any_in_range(&0, &1));
+
+        // the whole tree
+        assert!(t.has_any_in_range(&0, &1000));
 
+        for lo in range(0u, 60) {
+            for hi in range(lo, lo + 6) {
+                assert_eq!(t.has_any_in_range(&lo, &hi), t.range(&lo, &hi).next().is_some());
+            }
+        }
+        let empty: Tree<uint, ()> = Tree::new();
+        assert!(!empty.has_any_in_range(&0, &10));
+    }
 
-    let mut rng = rand::task_rng();
-    let between = Range::new(0u, 60);
+    #[test]
+    fn test_take() {
+        let mut t = Tree::new();
+        for i in range(0u, 50) {
+            t.insert(Tagged { id: i, tag: 'a' }, i);
+        }
 
-    let mut t = Tree::new();
+        // the stored key comes back, not the one asked with
+        let taken = t.take(&Tagged { id: 20, tag: 'z' });
+        match taken {
+            Some((k, v)) => {
+                assert_eq!((k.id, k.tag, v), (20, 'a', 20));
+            },
+            None => fail!("take: key not found"),
+        }
+        assert!(t.take(&Tagged { id: 20, tag: 'a' }).is_none());
+        assert_eq!(t.len(), 49);
+        assert!(t.is_aa() && t.is_size_consistent());
 
-    for _ in range(0u, 22) {
-        let a = between.ind_sample(&mut rng);
-        t.insert(a, ());
+        for i in range(0u, 50).filter(|&i| i != 20) {
+            assert!(t.take(&Tagged { id: i, tag: 'b' }).is_some());
+            assert!(t.is_aa());
+        }
+        assert!(t.is_empty());
     }
-    print_tree(&t);
 
-    let a = between.ind_sample(&mut rng);
-    t.insert(a, ());
-    print_tree(&t);
+    #[test]
+    fn test_duplicate_policy_overwrite() {
+        let mut t = Tree::with_policy(DuplicatePolicy::Overwrite);
+        assert_eq!(t.insert(1u, 'a'), None);
+        assert_eq!(t.insert(1u, 'b'), Some('a'));
+        assert_eq!(t.find(&1), Some(&'b'));
+        assert_eq!(t.len(), 1);
+    }
 
-    println!("-------------");
-    let mut x = Tree::new();
-    x.insert(3u, ());
-    print_tree(&x);
+    #[test]
+    fn test_duplicate_policy_keep() {
+        let mut t = Tree::with_policy(DuplicatePolicy::Keep);
+        for i in range(0u, 100) {
+            assert_eq!(t.insert(i, i), None);
+        }
+        for i in range(0u, 100) {
+            assert_eq!(t.insert(i, i + 1), Some(i + 1));
+        }
+        assert_eq!(t.len(), 100);
+        assert!(t.iter().all(|(k, v)| k == v));
+        assert!(t.is_aa() && t.is_size_consistent());
 
-    x.insert(7u, ());
-    print_tree(&x);
+        // trees split off this one keep its policy
+        let mut upper = t.split_off(&50);
+        assert_eq!(upper.insert(75, 0), Some(0));
+        assert_eq!(upper.find(&75), Some(&75));
+        assert_eq!(t.clone().insert(25, 0), Some(0));
+    }
 
-    x.insert(1u, ());
-    print_tree(&x);
+    #[test]
+    fn test_duplicate_policy_multi() {
+        let mut t = Tree::with_policy(DuplicatePolicy::Multi);
+        for &(k, v) in [(1u, 'a'), (2, 'b'), (1, 'c'), (0, 'd'), (1, 'e')].iter() {
+            assert_eq!(t.insert(k, v), None);
+        }
+        assert_eq!(t.len(), 5);
+        assert_eq!(t.get_all(&1), vec!(&'a', &'c', &'e'));
+        assert_eq!(t.get_all(&2), vec!(&'b'));
+        assert_eq!(t.get_all(&3), vec!());
+        assert!(t.contains_key(&1));
+        assert!(t.is_aa() && t.is_size_consistent());
 
-    x.insert(9u, ());
-    print_tree(&x);
+        assert!(t.remove(&1).is_some());
+        assert_eq!(t.len(), 4);
+        assert_eq!(t.get_all(&1).len(), 2);
+        assert!(t.is_aa());
 
-    x.insert(5u, ());
-    print_tree(&x);
+        // the values under each key stay in insertion order through rebalancing
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 10);
+        let mut t = Tree::with_policy(DuplicatePolicy::Multi);
+        let mut model: Vec<Vec<uint>> = Vec::from_fn(10, |_| vec!());
+        for i in range(0u, 500) {
+            let k = between.ind_sample(&mut rng);
+            t.insert(k, i);
+            model.get_mut(k).push(i);
+        }
+        assert_eq!(t.len(), 500);
+        assert!(t.is_aa() && t.is_size_consistent());
+        for k in range(0u, 10) {
+            let values: Vec<uint> = t.get_all(&k).into_iter().map(|&v| v).collect();
+            assert_eq!(values.as_slice(), model.get(k).as_slice());
+        }
 
-    x.insert(6u, ());
-    print_tree(&x);
+        // under the other policies there is at most one value per key
+        let mut t = Tree::new();
+        t.insert(1u, 'a');
+        t.insert(1u, 'b');
+        assert_eq!(t.get_all(&1), vec!(&'b'));
+    }
 
-    x.insert(4u, ());
-    print_tree(&x);
+    #[test]
+    fn test_find_closest() {
+        let mut t = Tree::new();
+        for &k in [10i, 20, 30, 45].iter() {
+            t.insert(k, k * 2);
+        }
 
-}
+        // nearest below, above, and exactly equal
+        assert_eq!(t.find_closest(&12), Some((&10, &20)));
+        assert_eq!(t.find_closest(&18), Some((&20, &40)));
+        assert_eq!(t.find_closest(&30), Some((&30, &60)));
 
+        // a tie goes to the smaller key
+        assert_eq!(t.find_closest(&25), Some((&20, &40)));
 
-mod test {
-    use super::Tree;
-    use std::rand;
-    use std::rand::distributions::{IndependentSample, Range};
+        // past either end
+        assert_eq!(t.find_closest(&-5), Some((&10, &20)));
+        assert_eq!(t.find_closest(&100), Some((&45, &90)));
+
+        let empty: Tree<int, int> = Tree::new();
+        assert_eq!(empty.find_closest(&3), None);
+    }
 
     #[test]
-    fn test_find() {
+    fn test_first_last_entry() {
+        let mut t: Tree<uint, uint> = Tree::new();
+        assert!(t.first_entry().is_none());
+        assert!(t.last_entry().is_none());
+
+        for i in range(0u, 50) {
+            t.insert(i, i * 2);
+        }
+
+        {
+            let mut first = t.first_entry().unwrap();
+            assert_eq!(*first.get(), 0);
+            *first.get_mut() = 5;
+            assert_eq!(first.remove(), 5);
+        }
+        assert_eq!(t.first_key_value(), Some((&1, &2)));
+        assert_eq!(t.len(), 49);
+        assert!(t.is_aa() && t.is_size_consistent());
+
+        {
+            let last = t.last_entry().unwrap();
+            *last.into_mut() += 1;
+        }
+        assert_eq!(t.last_key_value(), Some((&49, &99)));
+        assert_eq!(t.last_entry().unwrap().remove_entry(), (49, 99));
+        assert_eq!(t.last_key_value(), Some((&48, &96)));
+
+        // emptying the tree from both ends
+        while !t.is_empty() {
+            t.first_entry().unwrap().remove();
+            assert!(t.is_aa() && t.is_size_consistent());
+            if !t.is_empty() {
+                t.last_entry().unwrap().remove();
+                assert!(t.is_aa() && t.is_size_consistent());
+            }
+        }
+        assert_eq!(t.iter().next(), None);
+    }
+
+    #[test]
+    fn test_intersection() {
+        let mut a = Tree::new();
+        let mut b = Tree::new();
+        for i in range(0u, 60) {
+            a.insert(i, 'a');
+        }
+        for i in range(40u, 100) {
+            b.insert(i, 'b');
+        }
+
+        let expected: Vec<uint> = range(40u, 60).collect();
+        assert_eq!(a.intersection(&b).into_iter().map(|&k| k).collect::<Vec<uint>>(),
+                   expected);
+        assert_eq!(b.intersection(&a).into_iter().map(|&k| k).collect::<Vec<uint>>(),
+                   expected);
+        assert_eq!(a.intersection(&Tree::new()), vec!());
+    }
+
+    #[test]
+    fn test_difference() {
+        let mut a = Tree::new();
+        let mut b = Tree::new();
+        for i in range(0u, 60) {
+            a.insert(i * 2, 'a');
+        }
+        for i in range(0u, 60) {
+            b.insert(i * 3, 'b');
+        }
+
+        let (a_keys, b_keys): (Vec<uint>, Vec<uint>) =
+            (a.keys().map(|&k| k).collect(), b.keys().map(|&k| k).collect());
+        let keys = |v: Vec<&uint>| v.into_iter().map(|&k| k).collect::<Vec<uint>>();
+
+        // equal keys with different values are in both
+        assert_eq!(keys(a.intersection(&b)),
+                   a_keys.iter().map(|&k| k).filter(|k| b_keys.contains(k)).collect());
+        assert_eq!(keys(a.difference(&b)),
+                   a_keys.iter().map(|&k| k).filter(|k| !b_keys.contains(k)).collect());
+        assert_eq!(keys(b.difference(&a)),
+                   b_keys.iter().map(|&k| k).filter(|k| !a_keys.contains(k)).collect());
+
+        let mut either: Vec<uint> = keys(a.difference(&b));
+        either.push_all(keys(b.difference(&a)).as_slice());
+        either.sort();
+        assert_eq!(keys(a.symmetric_difference(&b)), either);
+        assert_eq!(keys(b.symmetric_difference(&a)), either);
+
+        assert_eq!(keys(a.difference(&a)), vec!());
+        assert_eq!(keys(a.symmetric_difference(&a)), vec!());
+        assert_eq!(keys(a.difference(&Tree::new())), a_keys);
+    }
+
+    #[test]
+    fn test_entry_or_insert_with_key() {
+        #[deriving(PartialEq, Show)]
+        struct Labelled {
+            id: uint,
+            label: String
+        }
+
         let mut t = Tree::new();
-        assert_eq!(t.find(&1u), None);
-        t.insert(1u, 'j');
-        assert_eq!(t.find(&1u), Some(&'j'));
+        for i in range(0u, 40) {
+            let v = t.entry(i * 2).or_insert_with_key(|&k| {
+                Labelled { id: k, label: k.to_string() }
+            });
+            assert_eq!(v.id, i * 2);
+        }
+        assert_eq!(t.len(), 40);
+        assert!(t.is_aa() && t.is_size_consistent());
+        assert_eq!(t.find(&14), Some(&Labelled { id: 14, label: "14".to_string() }));
 
+        // present keys are left alone, and the factory isn't called
+        {
+            let v = t.entry(14).or_insert_with_key(|_| fail!("called for a present key"));
+            v.label.push_str("!");
+        }
+        assert_eq!(t.len(), 40);
+        assert_eq!(t.find(&14).unwrap().label.as_slice(), "14!");
+
+        assert_eq!(*t.entry(100).or_insert(Labelled { id: 1, label: String::new() }),
+                   Labelled { id: 1, label: String::new() });
+        assert_eq!(t.entry(100).or_insert_with(|| fail!("called for a present key")).id, 1);
+        assert_eq!(t.len(), 41);
     }
 
-    // testing whether we can find all the things we inserted
     #[test]
-    fn test_insert() {
-        let mut t: Tree<uint, u8> = Tree::new();
-        for (i, c) in range(0u, 10).zip(range(b'a', b'z')) {
-            t.insert(i, c);
+    fn test_multimap_retain_values() {
+        let mut m = MultiMap::new();
+        for k in range(0u, 10) {
+            for v in range(0u, k % 4) {
+                m.append_value(k, k + v * 2);
+            }
         }
 
-        for (ref i, ref c) in range(0u, 10).zip(range(b'a', b'z')) {
-            assert_eq!(t.find(i), Some(c));
+        m.retain_values(|_, &v| v % 2 == 0);
+        assert!(m.is_aa());
+        for k in range(0u, 10) {
+            let expected: Vec<uint> = if k % 2 == 0 {
+                range(0u, k % 4).map(|v| k + v * 2).collect()
+            } else {
+                vec!()
+            };
+            assert_eq!(m.get_all(&k), expected.as_slice());
         }
+        // odd keys had only odd values, and keys 0, 4 and 8 had none to begin with
+        assert_eq!(m.tree.len(), 2);
+        assert_eq!(m.len(), m.iter_all().count());
+        assert_eq!(m.len(), 4);
+    }
 
-        assert_eq!(t.find(&10u), None);
+    #[test]
+    fn test_multimap_insert_replaces() {
+        let mut m = MultiMap::new();
+        assert_eq!(m.insert(1u, 'a'), vec!());
+        m.append_value(1u, 'b');
+        m.append_value(2u, 'c');
+        assert_eq!(m.len(), 3);
+
+        assert_eq!(m.insert(1u, 'd'), vec!('a', 'b'));
+        assert_eq!(m.get_all(&1), ['d'].as_slice());
+        assert_eq!(m.get_all(&2), ['c'].as_slice());
+        assert_eq!(m.len(), 2);
+        assert!(m.is_aa());
     }
 
-    fn insert_n_check_aa(n: uint, between: Range<uint>, rng: &mut rand::TaskRng) {
+    #[test]
+    fn test_multimap_append_value() {
+        let mut m = MultiMap::new();
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 20);
+        let mut model: TreeMap<uint, Vec<uint>> = TreeMap::new();
+        for i in range(0u, 300) {
+            let k = between.ind_sample(&mut rng);
+            m.append_value(k, i);
+            if !model.contains_key(&k) {
+                model.insert(k, vec!());
+            }
+            model.find_mut(&k).unwrap().push(i);
+        }
+
+        assert_eq!(m.len(), 300);
+        assert!(m.is_aa());
+        for k in range(0u, 20) {
+            match model.find(&k) {
+                Some(values) => assert_eq!(m.get_all(&k), values.as_slice()),
+                None => assert_eq!(m.get_all(&k), &[]),
+            }
+        }
+    }
+
+    #[test]
+    fn test_cursor_mut_navigation() {
         let mut t = Tree::new();
+        for i in range(0u, 5) {
+            t.insert(i, i * 10);
+        }
 
-        for _ in range(0u, n) {
-            let a = between.ind_sample(rng);
-            println!("{}", a);
-            t.insert(a, ());
+        let mut c = t.cursor_mut();
+        assert_eq!(c.index(), Some(0));
+        assert_eq!(c.current().map(|(&k, v)| (k, *v)), Some((0, 0)));
+        c.move_next();
+        c.move_next();
+        assert_eq!(c.current().map(|(&k, v)| (k, *v)), Some((2, 20)));
+        c.move_prev();
+        assert_eq!(c.current().map(|(&k, v)| (k, *v)), Some((1, 10)));
+        c.move_prev();
+        c.move_prev();
+        assert_eq!(c.index(), None);
+        assert!(c.current().is_none());
+        c.move_prev();
+        assert_eq!(c.current().map(|(&k, v)| (k, *v)), Some((4, 40)));
+        c.move_next();
+        assert!(c.current().is_none());
+        c.move_next();
+        assert_eq!(c.current().map(|(&k, v)| (k, *v)), Some((0, 0)));
+
+        // changing values as it goes
+        loop {
+            match c.current() {
+                Some((&k, v)) => *v = k + 1,
+                None => break,
+            }
+            c.move_next();
         }
+        assert_eq!(t.values().map(|&v| v).collect::<Vec<uint>>(), vec!(1, 2, 3, 4, 5));
 
-        assert!(t.is_aa());
+        let mut empty: Tree<uint, uint> = Tree::new();
+        let mut c = empty.cursor_mut();
+        assert!(c.current().is_none());
+        c.move_next();
+        c.move_prev();
+        assert!(c.remove_current().is_none());
     }
 
-    // testing whether, after inserting 20 random keys, is_aa() returns true
     #[test]
-    fn test_insert_is_aa() {
+    fn test_cursor_mut_remove() {
+        let mut t = Tree::new();
+        for i in range(0u, 200) {
+            t.insert(i, i);
+        }
+
+        {
+            // remove every other entry, from the first on
+            let mut c = t.cursor_mut();
+            loop {
+                match c.remove_current() {
+                    Some((k, v)) => assert_eq!((k % 2, k), (0, v)),
+                    None => break,
+                }
+                c.move_next();
+            }
+        }
+        assert_eq!(t.len(), 100);
+        assert!(t.is_aa() && t.is_size_consistent());
+        assert_eq!(t.keys().map(|&k| k).collect::<Vec<uint>>(),
+                   range(0u, 100).map(|i| i * 2 + 1).collect::<Vec<uint>>());
+
+        // and then remove the rest going backwards from the end
+        {
+            let mut c = t.cursor_mut();
+            c.move_prev();
+            c.move_prev();
+            assert_eq!(c.remove_current(), Some((199, 199)));
+            c.move_prev();
+            loop {
+                match c.remove_current() {
+                    Some(_) => c.move_prev(),
+                    None => break,
+                }
+            }
+        }
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn test_remove_keys() {
         let mut rng = rand::task_rng();
-        let between = Range::new(0u, 100_000);
+        let between = Range::new(0u, 2000);
 
-        for _ in range(0u, 300) {
-            insert_n_check_aa(20, between, &mut rng);
+        // both a handful of keys, removed one by one, and enough to rebuild
+        for &n in [5u, 300].iter() {
+            let mut t = Tree::new();
+            for i in range(0u, 1000) {
+                t.insert(i * 2, i);
+            }
+            let keys: Vec<uint> = range(0, n).map(|_| between.ind_sample(&mut rng)).collect();
+            let mut present: Vec<uint> = keys.iter().map(|&k| k)
+                                             .filter(|k| t.contains_key(k)).collect();
+            present.sort();
+            present.dedup();
+
+            assert_eq!(t.remove_keys(keys.as_slice()), present.len());
+            assert_eq!(t.len(), 1000 - present.len());
+            assert!(t.is_aa() && t.is_size_consistent());
+            for k in keys.iter() {
+                assert_eq!(t.find(k), None);
+            }
+            for i in range(0u, 1000) {
+                if !present.contains(&(i * 2)) {
+                    assert_eq!(t.find(&(i * 2)), Some(&i));
+                }
+            }
         }
     }
 
-    // testing whether, after inserting 20 random keys, is_aa() returns true,
-    // but this time some of the keys are repeated
     #[test]
-    fn test_insert_dups_is_aa() {
+    fn test_check_balance() {
+        let mut t = Tree::new();
+        for i in range(0u, 100) {
+            t.insert(i, ());
+        }
+        assert_eq!(t.check_balance(), Ok(()));
+
+        // a left child on the same level as its parent
+        let mut bad = t.clone();
+        bad.root.get_mut_ref().left.get_mut_ref().level += 1;
+        let err = bad.check_balance().unwrap_err();
+        assert!(err.as_slice().starts_with("left level"), "{}", err);
+        assert!(!bad.is_aa());
+
+        // a right child two levels down
+        let mut bad = Tree::new();
+        let mut n = leaf(1, 3);
+        n.left = Some(leaf(0, 2));
+        n.right = Some(leaf(2, 1));
+        n.size = 3;
+        bad.root = Some(n);
+        bad.size = 3;
+        let err = bad.check_balance().unwrap_err();
+        assert!(err.as_slice().starts_with("right level: 1 at level 3"), "{}", err);
+
+        // two horizontal links in a row
+        let mut bad = Tree::new();
+        let mut r = leaf(1, 1);
+        r.right = Some(leaf(2, 1));
+        r.size = 2;
+        let mut n = leaf(0, 1);
+        n.right = Some(r);
+        n.size = 3;
+        bad.root = Some(n);
+        bad.size = 3;
+        let err = bad.check_balance().unwrap_err();
+        assert!(err.as_slice().starts_with("grandchild level: 0"), "{}", err);
+
+        // keys out of order
+        let mut bad = t.clone();
+        bad.root.get_mut_ref().key = 1000;
+        assert_eq!(bad.check_balance(), Err("keys out of order".to_string()));
+    }
+
+    #[test]
+    fn test_vacant_entry_is_lazy() {
+        let mut t = Tree::new();
+        for i in range(0u, 50) {
+            t.insert(i * 2, i);
+        }
+        let before = t.clone();
+
+        match t.entry(33) {
+            Entry::Vacant(_) => {},
+            Entry::Occupied(_) => fail!("entry: 33 should be vacant"),
+        }
+        assert_eq!(t.len(), 50);
+        assert!(t == before);
+        assert_eq!(t.level_histogram(), before.level_histogram());
+        assert_eq!(t.root.get_ref().key, before.root.get_ref().key);
+        assert!(t.find(&33).is_none());
+
+        // only inserting through the entry changes anything
+        match t.entry(33) {
+            Entry::Vacant(e) => { e.insert(100); },
+            Entry::Occupied(_) => fail!("entry: 33 should be vacant"),
+        }
+        assert_eq!(t.len(), 51);
+        assert_eq!(t.find(&33), Some(&100));
+        assert!(t.is_aa() && t.is_size_consistent());
+    }
+
+    #[test]
+    fn test_into_keys_values() {
+        let mut t = Tree::new();
         let mut rng = rand::task_rng();
-        let between = Range::new(0u, 15);
+        let between = Range::new(0u, 1000);
+        for _ in range(0u, 200) {
+            let k = between.ind_sample(&mut rng);
+            t.insert(k.to_string(), k);
+        }
 
-        for _ in range(0u, 300) {
-            insert_n_check_aa(20, between, &mut rng);
+        let keys: Vec<String> = t.keys().map(|k| k.clone()).collect();
+        let values: Vec<uint> = t.values().map(|&v| v).collect();
+        let owned: Vec<String> = t.clone().into_keys().collect();
+        assert_eq!(owned, keys);
+        assert!(owned.as_slice().windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(t.into_values().collect::<Vec<uint>>(), values);
+    }
+
+    #[test]
+    fn test_extend_replacing() {
+        let mut t = Tree::new();
+        for i in range(0u, 50) {
+            t.insert(i, 0u);
+        }
+
+        // 40..50 are updated, 50..70 are new
+        assert_eq!(t.extend_replacing(range(40u, 70).map(|i| (i, i))), 20);
+        assert_eq!(t.len(), 70);
+        assert_eq!(t.find(&39), Some(&0));
+        assert_eq!(t.find(&45), Some(&45));
+        assert_eq!(t.find(&69), Some(&69));
+        assert!(t.is_aa() && t.is_size_consistent());
+
+        // the same goes when the tree would otherwise keep old values
+        let mut kept = Tree::with_policy(DuplicatePolicy::Keep);
+        kept.insert(1u, 'a');
+        assert_eq!(kept.extend_replacing(vec!((1u, 'b'), (2, 'c')).into_iter()), 1);
+        assert_eq!(kept.find(&1), Some(&'b'));
+    }
+
+    #[test]
+    fn test_median() {
+        let empty: Tree<uint, ()> = Tree::new();
+        assert_eq!(empty.median(), None);
+
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 1000);
+        for &n in [1u, 2, 7, 30, 101].iter() {
+            let mut t = Tree::new();
+            while t.len() < n {
+                t.insert(between.ind_sample(&mut rng), ());
+            }
+            let mut sorted: Vec<uint> = t.keys().map(|&k| k).collect();
+            sorted.sort();
+            assert_eq!(t.median().map(|(&k, _)| k), Some(sorted[n / 2]));
+        }
+
+        let mut t = Tree::new();
+        for i in range(0u, 4) {
+            t.insert(i, ());
+        }
+        assert_eq!(t.median(), Some((&2, &())));
+    }
+
+    #[test]
+    fn test_remove_if() {
+        let mut t = Tree::new();
+        for i in range(0u, 50) {
+            t.insert(i, i * 3);
+        }
+
+        // predicate true: removed
+        assert_eq!(t.remove_if(&10, |&v| v == 30), Some(30));
+        assert_eq!(t.find(&10), None);
+        assert_eq!(t.len(), 49);
+
+        // predicate false: retained
+        assert_eq!(t.remove_if(&11, |&v| v > 1000), None);
+        assert_eq!(t.find(&11), Some(&33));
+        assert_eq!(t.len(), 49);
+
+        // absent key: the predicate isn't even asked
+        assert_eq!(t.remove_if(&100, |_| fail!("called for an absent key")), None);
+
+        for i in range(0u, 50) {
+            t.remove_if(&i, |&v| v % 2 == 0);
+        }
+        assert!(t.values().all(|&v| v % 2 == 1));
+        assert!(t.is_aa() && t.is_size_consistent());
+    }
+
+    #[test]
+    fn test_fold_values() {
+        let mut t = Tree::new();
+        let mut rng = rand::task_rng();
+        let between = Range::new(0u, 1000);
+        for _ in range(0u, 200) {
+            let k = between.ind_sample(&mut rng);
+            t.insert(k, k * 3);
+        }
+
+        let mut sum = 0;
+        for (_, &v) in t.iter() {
+            sum += v;
+        }
+        assert_eq!(t.fold_values(0, |acc, &v| acc + v), sum);
+        assert_eq!(t.fold_values(vec!(), |mut acc, &v| { acc.push(v); acc }),
+                   t.values().map(|&v| v).collect::<Vec<uint>>());
+        assert_eq!(t.fold_values(0, |acc, &v| max(acc, v)), *t.values().max().unwrap());
+
+        let empty: Tree<uint, uint> = Tree::new();
+        assert_eq!(empty.fold_values(7, |acc, &v| acc + v), 7);
+    }
+
+    #[test]
+    fn test_multimap_eq() {
+        let mut a = MultiMap::new();
+        let mut b = MultiMap::new();
+        let mut c = MultiMap::new();
+        for &(k, v) in [(1u, 'x'), (2, 'y'), (1, 'z')].iter() {
+            a.append_value(k, v);
+            b.append_value(k, v);
+        }
+        // the same values, but 'z' went in under 1 before 'x'
+        for &(k, v) in [(1u, 'z'), (2, 'y'), (1, 'x')].iter() {
+            c.append_value(k, v);
+        }
+
+        assert!(a == b);
+        assert!(a.eq_unordered(&b));
+        assert!(a != c);
+        assert!(a.eq_unordered(&c));
+
+        c.append_value(2, 'y');
+        assert!(!a.eq_unordered(&c));
+        b.remove_value(&2, &'y');
+        b.append_value(3, 'y');
+        assert!(a != b);
+        assert!(!a.eq_unordered(&b));
+    }
+
+    #[test]
+    fn test_root_key() {
+        let mut t = Tree::new();
+        assert_eq!(t.root_key(), None);
+        t.insert(5u, ());
+        assert_eq!(t.root_key(), Some(&5));
+
+        for i in range(0u, 1000) {
+            t.insert(i, ());
+        }
+        let root = *t.root_key().unwrap();
+        let (&lo, _) = t.first_key_value().unwrap();
+        let (&hi, _) = t.last_key_value().unwrap();
+        assert!(lo < root && root < hi);
+    }
+
+    #[test]
+    fn test_print_tree_capped() {
+        // 3 at the root, 1 and 5 below it, and the rest below those
+        let mut t = Tree::new();
+        for i in range(0u, 7) {
+            t.insert(i, ());
+        }
+
+        let mut w = MemWriter::new();
+        write_node_depth(&mut w, &t.root, 0, Some(2)).unwrap();
+        let out = String::from_utf8(w.unwrap()).unwrap();
+        assert_eq!(out.as_slice().lines().collect::<Vec<&str>>(),
+                   vec!("3:()*3",
+                        "   1:()*2",
+                        "      ...",
+                        "      ...",
+                        "   5:()*2",
+                        "      ...",
+                        "      ..."));
+
+        let mut w = MemWriter::new();
+        write_node_depth(&mut w, &t.root, 0, None).unwrap();
+        let out = String::from_utf8(w.unwrap()).unwrap();
+        assert!(!out.as_slice().contains("..."));
+        assert!(out.as_slice().contains("0:()*1"));
+    }
+
+    #[test]
+    fn test_entry_key() {
+        let mut t = Tree::new();
+        t.insert(Tagged { id: 1, tag: 'a' }, ());
+
+        // an occupied entry has the key the tree already had
+        match t.entry(Tagged { id: 1, tag: 'b' }) {
+            Entry::Occupied(e) => assert_eq!(e.key().tag, 'a'),
+            Entry::Vacant(_) => fail!("entry: 1 should be occupied"),
+        }
+        match t.entry(Tagged { id: 2, tag: 'c' }) {
+            Entry::Vacant(e) => assert_eq!((e.key().id, e.key().tag), (2, 'c')),
+            Entry::Occupied(_) => fail!("entry: 2 should be vacant"),
+        }
+        assert_eq!(t.entry(Tagged { id: 1, tag: 'd' }).key().tag, 'a');
+        assert_eq!(t.entry(Tagged { id: 3, tag: 'e' }).key().tag, 'e');
+        assert_eq!(t.first_entry().unwrap().key().id, 1);
+        assert_eq!(t.len(), 1);
+    }
+
+    #[test]
+    fn test_select_or_err() {
+        let mut t = Tree::new();
+        for i in range(0u, 30) {
+            t.insert(i * 2, i);
+        }
+
+        assert_eq!(t.select_or_err(0), Ok((&0, &0)));
+        assert_eq!(t.select_or_err(17), Ok((&34, &17)));
+        assert_eq!(t.select_or_err(29), Ok((&58, &29)));
+        assert_eq!(t.select_or_err(30), Err(30));
+        assert_eq!(t.select_or_err(1000), Err(30));
+
+        let empty: Tree<uint, uint> = Tree::new();
+        assert_eq!(empty.select_or_err(0), Err(0));
+    }
+
+    #[test]
+    fn test_insert_with() {
+        let mut buckets = Tree::new();
+        let incoming = [(3u, 10u), (1, 5), (3, 7), (2, 1), (1, 1), (3, 2)];
+        for &(k, v) in incoming.iter() {
+            buckets.insert_with(k, v, |total, v| *total += v);
         }
+
+        assert_eq!(buckets.len(), 3);
+        assert_eq!(buckets.find(&1), Some(&6));
+        assert_eq!(buckets.find(&2), Some(&1));
+        assert_eq!(buckets.find(&3), Some(&19));
+        assert!(buckets.is_aa());
+
+        assert_eq!(buckets.insert_with(3, 1, |total, v| *total *= v + 1).map(|v| *v),
+                   Some(38));
+        assert_eq!(buckets.insert_with(4, 9, |_, _| fail!("merged a new key")).map(|v| *v),
+                   Some(9));
+    }
+
+    #[test]
+    fn test_verify_size() {
+        let mut t = Tree::new();
+        assert!(t.verify_size());
+        for i in range(0u, 50) {
+            t.insert(i * 7 % 50, i);
+        }
+        assert!(t.verify_size());
+
+        t.set_size_for_test(51);
+        assert!(!t.verify_size());
+        t.set_size_for_test(49);
+        assert!(!t.verify_size());
+        t.set_size_for_test(50);
+        assert!(t.verify_size());
     }
 }